@@ -7,7 +7,7 @@
       default = self.packages.${pkgs.stdenv.system}.buzzrelay;
     };
     streams = mkOption {
-      type = with types; listOf str;
+      type = with types; listOf (either str attrs);
       default = [
         "https://fedi.buzz/api/v1/streaming/public"
       ];
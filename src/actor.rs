@@ -11,28 +11,106 @@ pub enum ActorKind {
     TagRelay(String),
     InstanceRelay(String),
     LanguageRelay(String),
+    /// Posts by one author, as `user@host`
+    AccountRelay(String),
+    /// Posts with a tag in a language
+    TagLanguageRelay(String, String),
+    /// Posts with any of these tags, sorted
+    TagsRelay(Vec<String>),
+    /// Posts that contain a word or phrase, lowercase with single
+    /// spaces
+    KeywordRelay(String),
+}
+
+fn normalize_tag(tag: &str) -> String {
+    deunicode(tag)
+        .to_lowercase()
+        .replace(char::is_whitespace, "")
+        .replace('-', "")
+}
+
+/// `de_CH` -> `de`, also for filters by language
+pub fn normalize_language(language: &str) -> String {
+    language.to_lowercase()
+        .chars()
+        .take_while(|c| c.is_alphabetic())
+        .collect()
 }
 
 impl ActorKind {
     pub fn from_tag(tag: &str) -> Self {
-        let tag = deunicode(tag)
-            .to_lowercase()
-            .replace(char::is_whitespace, "")
-            .replace('-', "");
-        ActorKind::TagRelay(tag)
+        ActorKind::TagRelay(normalize_tag(tag))
     }
 
     pub fn from_language(language: &str) -> Option<Self> {
-        let language = language.to_lowercase()
-            .chars()
-            .take_while(|c| c.is_alphabetic())
-            .collect::<String>();
+        let language = normalize_language(language);
         if language.is_empty() {
             None
         } else {
             Some(ActorKind::LanguageRelay(language))
         }
     }
+
+    pub fn from_tag_language(tag: &str, language: &str) -> Option<Self> {
+        let tag = normalize_tag(tag);
+        let language = normalize_language(language);
+        if tag.is_empty() || language.is_empty() {
+            None
+        } else {
+            Some(ActorKind::TagLanguageRelay(tag, language))
+        }
+    }
+
+    /// From tags joined by `+`, a single tag being a plain `TagRelay`
+    pub fn from_tags(tags: &str) -> Option<Self> {
+        let mut tags = tags.split('+')
+            .map(normalize_tag)
+            .filter(|tag| ! tag.is_empty())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        match tags.len() {
+            0 => None,
+            1 => Some(ActorKind::TagRelay(tags.remove(0))),
+            _ => Some(ActorKind::TagsRelay(tags)),
+        }
+    }
+
+    /// The tags by which posts are looked up for composite kinds
+    pub fn composite_tags(&self) -> &[String] {
+        match self {
+            ActorKind::TagLanguageRelay(tag, _) =>
+                std::slice::from_ref(tag),
+            ActorKind::TagsRelay(tags) =>
+                tags,
+            _ =>
+                &[],
+        }
+    }
+
+    pub fn from_keyword(keyword: &str) -> Option<Self> {
+        let keyword = keyword.to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if keyword.is_empty() || keyword.contains('/') {
+            None
+        } else {
+            Some(ActorKind::KeywordRelay(keyword))
+        }
+    }
+
+    pub fn from_account(account: &str) -> Option<Self> {
+        let account = account.strip_prefix('@')
+            .unwrap_or(account)
+            .to_lowercase();
+        let (user, host) = account.split_once('@')?;
+        if user.is_empty() || host.is_empty() || host.contains('@') || account.contains('/') {
+            None
+        } else {
+            Some(ActorKind::AccountRelay(account))
+        }
+    }
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -45,7 +123,18 @@ impl Actor {
     pub fn from_uri(mut uri: &str) -> Option<Self> {
         let kind;
         let host;
-        if uri.starts_with("acct:tag-") {
+        if uri.starts_with("acct:tag-") && uri.split('@').next().is_some_and(|user| user.contains("-language-")) {
+            let off = "acct:tag-".len();
+            let at = uri.find('@')?;
+            let (tag, language) = uri.get(off..at)?.rsplit_once("-language-")?;
+            kind = ActorKind::from_tag_language(tag, language)?;
+            host = Arc::new(uri[at + 1..].to_string());
+        } else if uri.starts_with("acct:tags-") {
+            let off = "acct:tags-".len();
+            let at = uri.find('@')?;
+            kind = ActorKind::from_tags(uri.get(off..at)?)?;
+            host = Arc::new(uri[at + 1..].to_string());
+        } else if uri.starts_with("acct:tag-") {
             let off = "acct:tag-".len();
             let at = uri.find('@')?;
             kind = ActorKind::from_tag(&uri[off..at]);
@@ -60,10 +149,30 @@ impl Actor {
             let at = uri.find('@')?;
             kind = ActorKind::from_language(&uri[off..at])?;
             host = Arc::new(uri[at + 1..].to_string());
+        } else if uri.starts_with("acct:keyword-") {
+            let off = "acct:keyword-".len();
+            let at = uri.rfind('@')?;
+            let keyword = urlencoding::decode(uri.get(off..at)?).ok()?;
+            kind = ActorKind::from_keyword(&keyword)?;
+            host = Arc::new(uri[at + 1..].to_string());
+        } else if uri.starts_with("acct:account-") {
+            // the account itself contains an `@`
+            let off = "acct:account-".len();
+            let at = uri.rfind('@')?;
+            kind = ActorKind::from_account(uri.get(off..at)?)?;
+            host = Arc::new(uri[at + 1..].to_string());
         } else if uri.starts_with("https://") {
             uri = &uri[8..];
 
             let parts = uri.split('/').collect::<Vec<_>>();
+            if parts.len() == 5 && parts[1] == "tag" && parts[3] == "language" {
+                let Ok(tag) = urlencoding::decode(parts[2]) else { return None; };
+                let Ok(language) = urlencoding::decode(parts[4]) else { return None; };
+                return Some(Actor {
+                    host: Arc::new(parts[0].to_string()),
+                    kind: ActorKind::from_tag_language(&tag, &language)?,
+                });
+            }
             if parts.len() != 3 {
                 return None;
             }
@@ -76,6 +185,12 @@ impl Actor {
                     ActorKind::InstanceRelay(topic.to_string()),
                 "language" =>
                     ActorKind::LanguageRelay(topic.to_string()),
+                "account" =>
+                    ActorKind::from_account(&topic)?,
+                "tags" =>
+                    ActorKind::from_tags(&topic)?,
+                "keyword" =>
+                    ActorKind::from_keyword(&topic)?,
                 _ =>
                     return None,
             };
@@ -114,6 +229,34 @@ impl Actor {
                 format!("https://{}/instance/{}", self.host, instance),
             ActorKind::LanguageRelay(language) =>
                 format!("https://{}/language/{}", self.host, language),
+            ActorKind::AccountRelay(account) =>
+                format!("https://{}/account/{}", self.host, account),
+            ActorKind::TagLanguageRelay(tag, language) =>
+                format!("https://{}/tag/{}/language/{}", self.host, tag, language),
+            ActorKind::TagsRelay(tags) =>
+                format!("https://{}/tags/{}", self.host, tags.join("+")),
+            ActorKind::KeywordRelay(keyword) =>
+                format!("https://{}/keyword/{}", self.host, urlencoding::encode(keyword)),
+        }
+    }
+
+    /// Also the webfinger `acct:` user
+    pub fn username(&self) -> String {
+        match &self.kind {
+            ActorKind::TagRelay(tag) =>
+                format!("tag-{tag}"),
+            ActorKind::InstanceRelay(instance) =>
+                format!("instance-{instance}"),
+            ActorKind::LanguageRelay(language) =>
+                format!("language-{language}"),
+            ActorKind::AccountRelay(account) =>
+                format!("account-{account}"),
+            ActorKind::TagLanguageRelay(tag, language) =>
+                format!("tag-{tag}-language-{language}"),
+            ActorKind::TagsRelay(tags) =>
+                format!("tags-{}", tags.join("+")),
+            ActorKind::KeywordRelay(keyword) =>
+                format!("keyword-{}", urlencoding::encode(keyword)),
         }
     }
 
@@ -136,6 +279,17 @@ impl Actor {
                     instance.to_string(),
                 ActorKind::LanguageRelay(language) =>
                     format!("in {language}"),
+                ActorKind::AccountRelay(account) =>
+                    format!("by @{account}"),
+                ActorKind::TagLanguageRelay(tag, language) =>
+                    format!("#{tag} in {language}"),
+                ActorKind::TagsRelay(tags) =>
+                    tags.iter()
+                    .map(|tag| format!("#{tag}"))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                ActorKind::KeywordRelay(keyword) =>
+                    format!("\"{keyword}\""),
             }),
             icon: Some(activitypub::Media {
                 media_type: Some("Image".to_string()),
@@ -152,14 +306,67 @@ impl Actor {
                 owner: Some(self.uri()),
                 pem: pub_key.to_pem().unwrap(),
             },
-            preferred_username: Some(match &self.kind {
-                ActorKind::TagRelay(tag) =>
-                    format!("tag-{tag}"),
-                ActorKind::InstanceRelay(instance) =>
-                    format!("instance-{instance}"),
-                ActorKind::LanguageRelay(language) =>
-                    format!("language-{language}"),
-            }),
+            preferred_username: Some(self.username()),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn account() {
+        assert_eq!(ActorKind::from_account("@Alice@Example.com"), Some(ActorKind::AccountRelay("alice@example.com".to_string())));
+        assert_eq!(ActorKind::from_account("alice"), None);
+        assert_eq!(ActorKind::from_account("alice@"), None);
+        assert_eq!(ActorKind::from_account("a@b@c"), None);
+    }
+
+    #[test]
+    fn account_uris() {
+        let actor = Actor::from_uri("acct:account-alice@example.com@relay.fedi.buzz").unwrap();
+        assert_eq!(actor.host.as_str(), "relay.fedi.buzz");
+        assert_eq!(actor.kind, ActorKind::AccountRelay("alice@example.com".to_string()));
+        assert_eq!(actor.uri(), "https://relay.fedi.buzz/account/alice@example.com");
+        assert_eq!(Actor::from_uri(&actor.uri()), Some(actor.clone()));
+        assert_eq!(Actor::from_uri("https://relay.fedi.buzz/account/alice%40example.com"), Some(actor));
+        assert_eq!(Actor::from_uri("acct:account-alice@relay.fedi.buzz"), None);
+    }
+
+    #[test]
+    fn tag_language_uris() {
+        let actor = Actor::from_uri("https://relay.fedi.buzz/tag/Rust/language/en_US").unwrap();
+        assert_eq!(actor.kind, ActorKind::TagLanguageRelay("rust".to_string(), "en".to_string()));
+        assert_eq!(actor.uri(), "https://relay.fedi.buzz/tag/rust/language/en");
+        let webfinger = format!("acct:{}@relay.fedi.buzz", actor.username());
+        assert_eq!(Actor::from_uri(&webfinger), Some(actor));
+        assert_eq!(Actor::from_uri("https://relay.fedi.buzz/tag/rust/language/"), None);
+        // only the user part is looked at
+        let actor = Actor::from_uri("acct:tag-rust@relay-language-x.example").unwrap();
+        assert_eq!(actor.kind, ActorKind::TagRelay("rust".to_string()));
+        assert_eq!(actor.host.as_str(), "relay-language-x.example");
+    }
+
+    #[test]
+    fn tags_uris() {
+        let actor = Actor::from_uri("https://relay.fedi.buzz/tags/rustlang+Rust+rust").unwrap();
+        assert_eq!(actor.kind, ActorKind::TagsRelay(vec!["rust".to_string(), "rustlang".to_string()]));
+        assert_eq!(actor.uri(), "https://relay.fedi.buzz/tags/rust+rustlang");
+        let webfinger = format!("acct:{}@relay.fedi.buzz", actor.username());
+        assert_eq!(Actor::from_uri(&webfinger), Some(actor));
+        assert_eq!(ActorKind::from_tags("rust+"), Some(ActorKind::TagRelay("rust".to_string())));
+        assert_eq!(ActorKind::from_tags("+"), None);
+    }
+
+    #[test]
+    fn keyword_uris() {
+        let actor = Actor::from_uri("https://relay.fedi.buzz/keyword/Open%20%20Source").unwrap();
+        assert_eq!(actor.kind, ActorKind::KeywordRelay("open source".to_string()));
+        assert_eq!(actor.uri(), "https://relay.fedi.buzz/keyword/open%20source");
+        let webfinger = format!("acct:{}@relay.fedi.buzz", actor.username());
+        assert_eq!(Actor::from_uri(&webfinger), Some(actor));
+        assert_eq!(ActorKind::from_keyword("c++"), Some(ActorKind::KeywordRelay("c++".to_string())));
+        assert_eq!(ActorKind::from_keyword(" "), None);
+    }
+}
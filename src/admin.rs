@@ -0,0 +1,399 @@
+use std::sync::Arc;
+use axum::{
+    extract::{Query, Request, State as AxumState},
+    http::{header::AUTHORIZATION, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use crate::{actor::Actor, blocklist, filter::Filter, follow, state::State, track_request};
+
+/// Page size when none is requested
+const DEFAULT_LIMIT: i64 = 100;
+/// Upper bound for the requested page size
+const MAX_LIMIT: i64 = 1000;
+
+/// Identifies a follow by the follower and the followed actor
+#[derive(Deserialize)]
+struct FollowRef {
+    id: String,
+    actor: String,
+}
+
+#[derive(Deserialize)]
+struct FollowFilter {
+    id: String,
+    actor: String,
+    filter: Filter,
+}
+
+#[derive(Deserialize)]
+struct Page {
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+}
+
+impl Page {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    fn offset(&self) -> i64 {
+        self.offset.max(0)
+    }
+}
+
+/// Extracted next to a [`Page`], as `serde(flatten)` would only pass
+/// strings on to it
+#[derive(Deserialize)]
+struct FollowsQuery {
+    /// Only the followers of this actor
+    actor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DomainBlock {
+    domain: String,
+}
+
+fn internal_error(method: &'static str, controller: &'static str, e: impl std::fmt::Display) -> Response {
+    track_request(method, controller, "error");
+    (StatusCode::INTERNAL_SERVER_ERROR,
+     format!("{e}")
+    ).into_response()
+}
+
+/// The admin API, only reachable with `Authorization: Bearer <token>`
+pub fn router(token: String) -> Router<State> {
+    Router::new()
+        .route("/pending", get(get_pending))
+        .route("/pending/approve", post(post_approve))
+        .route("/pending/reject", post(post_reject))
+        .route("/follows", get(get_follows))
+        .route("/follows/remove", post(post_remove_follow))
+        .route("/follows/filter", post(post_follow_filter))
+        .route("/blocks", get(get_blocks).post(post_block))
+        .route("/blocks/remove", post(post_unblock))
+        .route("/health", get(get_health))
+        .route("/streams", get(get_streams))
+        .layer(middleware::from_fn_with_state(Arc::new(token), authorize))
+}
+
+async fn authorize(
+    AxumState(token): AxumState<Arc<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // without revealing how much of the token matched
+        .is_some_and(|value| ! token.is_empty() && bool::from(value.as_bytes().ct_eq(token.as_bytes())));
+    if ! authorized {
+        let method = match *request.method() {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            _ => "OTHER",
+        };
+        track_request(method, "admin", "unauthorized");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn get_pending(
+    AxumState(state): AxumState<State>,
+) -> Response {
+    match state.database.get_pending_follows().await {
+        Ok(pending) => {
+            track_request("GET", "admin_pending", "ok");
+            Json(pending.into_iter()
+                 .map(|pending| json!({
+                     "id": pending.id,
+                     "inbox": pending.inbox,
+                     "actor": pending.actor,
+                     "filter": pending.filter,
+                     "created_at": pending.created_at,
+                 }))
+                 .collect::<Vec<_>>()
+            ).into_response()
+        }
+        Err(e) => {
+            tracing::error!("get_pending_follows: {}", e);
+            internal_error("GET", "admin_pending", e)
+        }
+    }
+}
+
+async fn post_approve(
+    AxumState(state): AxumState<State>,
+    Json(pending): Json<FollowRef>,
+) -> Response {
+    respond(&state, pending, "Accept").await
+}
+
+async fn post_reject(
+    AxumState(state): AxumState<State>,
+    Json(pending): Json<FollowRef>,
+) -> Response {
+    respond(&state, pending, "Reject").await
+}
+
+/// Sends the decision on a pending follow and forgets about it
+async fn respond(state: &State, pending: FollowRef, action_type: &str) -> Response {
+    let pending = match state.database.get_pending_follow(&pending.id, &pending.actor).await {
+        Ok(Some(pending)) => pending,
+        Ok(None) => {
+            track_request("POST", "admin_pending", "not_found");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(e) => {
+            tracing::error!("get_pending_follow: {}", e);
+            return internal_error("POST", "admin_pending", e);
+        }
+    };
+    let Some(target) = Actor::from_uri(&pending.actor) else {
+        track_request("POST", "admin_pending", "bad_actor");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let follow = serde_json::from_str(&pending.follow)
+        .unwrap_or(serde_json::Value::Null);
+
+    if let Err(e) = follow::send_response(state, &target, &pending.id, &pending.inbox, follow, action_type).await {
+        tracing::error!("follow::send_response {} {}: {}", action_type, pending.id, e);
+        track_request("POST", "admin_pending", "send_error");
+        return (StatusCode::BAD_GATEWAY,
+                format!("{e}")
+        ).into_response();
+    }
+    if action_type == "Accept" {
+        if let Err(e) = state.database.add_follow(&pending.id, &pending.inbox, &pending.actor, pending.shared_inbox, &pending.filter).await {
+            tracing::error!("add_follow: {}", e);
+            return internal_error("POST", "admin_pending", e);
+        }
+    }
+    if let Err(e) = state.database.del_pending_follow(&pending.id, &pending.actor).await {
+        tracing::error!("del_pending_follow: {}", e);
+    }
+
+    tracing::info!("{} follow of {} by {}", action_type, pending.actor, pending.id);
+    track_request("POST", "admin_pending", if action_type == "Accept" { "approved" } else { "rejected" });
+    if action_type == "Accept" {
+        let state = state.clone();
+        tokio::spawn(async move {
+            follow::record_software(&state, &pending.id, &pending.inbox).await;
+        });
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn get_streams(
+    AxumState(state): AxumState<State>,
+) -> Response {
+    track_request("GET", "admin_streams", "ok");
+    Json(state.stream_statuses.snapshot())
+        .into_response()
+}
+
+async fn get_follows(
+    AxumState(state): AxumState<State>,
+    Query(query): Query<FollowsQuery>,
+    Query(page): Query<Page>,
+) -> Response {
+    match state.database.get_follows(query.actor.as_deref(), page.limit(), page.offset()).await {
+        Ok(follows) => {
+            track_request("GET", "admin_follows", "ok");
+            Json(follows).into_response()
+        }
+        Err(e) => {
+            tracing::error!("get_follows: {}", e);
+            internal_error("GET", "admin_follows", e)
+        }
+    }
+}
+
+/// Removes a follow without notifying the follower
+async fn post_remove_follow(
+    AxumState(state): AxumState<State>,
+    Json(follow): Json<FollowRef>,
+) -> Response {
+    match state.database.del_follow(&follow.id, &follow.actor).await {
+        Ok(()) => {
+            tracing::info!("removed follow of {} by {}", follow.actor, follow.id);
+            track_request("POST", "admin_follows", "removed");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            tracing::error!("del_follow: {}", e);
+            internal_error("POST", "admin_follows", e)
+        }
+    }
+}
+
+/// Changes what a follower gets delivered
+async fn post_follow_filter(
+    AxumState(state): AxumState<State>,
+    Json(follow): Json<FollowFilter>,
+) -> Response {
+    match state.database.set_follow_filter(&follow.id, &follow.actor, &follow.filter).await {
+        Ok(true) => {
+            tracing::info!("filter of {} by {}: {}", follow.actor, follow.id, follow.filter);
+            track_request("POST", "admin_follows", "filtered");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => {
+            track_request("POST", "admin_follows", "not_found");
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            tracing::error!("set_follow_filter: {}", e);
+            internal_error("POST", "admin_follows", e)
+        }
+    }
+}
+
+async fn get_blocks(
+    AxumState(state): AxumState<State>,
+) -> Response {
+    match state.database.get_domain_blocks().await {
+        Ok(domains) => {
+            track_request("GET", "admin_blocks", "ok");
+            Json(domains).into_response()
+        }
+        Err(e) => {
+            tracing::error!("get_domain_blocks: {}", e);
+            internal_error("GET", "admin_blocks", e)
+        }
+    }
+}
+
+/// Blocks a domain and drops all follows from it
+async fn post_block(
+    AxumState(state): AxumState<State>,
+    Json(block): Json<DomainBlock>,
+) -> Response {
+    let Some(domain) = blocklist::normalize(&block.domain) else {
+        track_request("POST", "admin_blocks", "invalid");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if let Err(e) = state.database.add_domain_block(&domain).await {
+        tracing::error!("add_domain_block: {}", e);
+        return internal_error("POST", "admin_blocks", e);
+    }
+    state.blocklist.add(domain.clone());
+
+    match state.database.del_domain_follows(&domain).await {
+        Ok(removed) => {
+            tracing::info!("blocked {}, removed {} follows", domain, removed.len());
+            track_request("POST", "admin_blocks", "blocked");
+            Json(json!({
+                "domain": domain,
+                "removed_follows": removed.len(),
+            })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("del_domain_follows: {}", e);
+            internal_error("POST", "admin_blocks", e)
+        }
+    }
+}
+
+async fn post_unblock(
+    AxumState(state): AxumState<State>,
+    Json(block): Json<DomainBlock>,
+) -> Response {
+    let Some(domain) = blocklist::normalize(&block.domain) else {
+        track_request("POST", "admin_blocks", "invalid");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    if let Err(e) = state.database.del_domain_block(&domain).await {
+        tracing::error!("del_domain_block: {}", e);
+        return internal_error("POST", "admin_blocks", e);
+    }
+    state.blocklist.remove(&domain);
+
+    tracing::info!("unblocked {}", domain);
+    track_request("POST", "admin_blocks", "unblocked");
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Delivery health per inbox, most failing first
+async fn get_health(
+    AxumState(state): AxumState<State>,
+    Query(page): Query<Page>,
+) -> Response {
+    match state.database.get_inbox_health(page.limit(), page.offset()).await {
+        Ok(health) => {
+            track_request("GET", "admin_health", "ok");
+            Json(health.into_iter()
+                 .map(|health| json!({
+                     "inbox": health.inbox,
+                     "failures": health.failures,
+                     "failing_since": health.failing_since,
+                     "last_success": health.last_success,
+                 }))
+                 .collect::<Vec<_>>()
+            ).into_response()
+        }
+        Err(e) => {
+            tracing::error!("get_inbox_health: {}", e);
+            internal_error("GET", "admin_health", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::to_bytes;
+    use sigh::alg::{Algorithm, RsaSha256};
+    use crate::{db::{Database, Follow}, stream::StreamStatuses};
+
+    async fn state() -> State {
+        let (priv_key, pub_key) = RsaSha256.generate_keys().unwrap();
+        State {
+            database: Database::connect("memory").await,
+            redis: None,
+            client: Arc::new(reqwest::Client::new()),
+            actor_cache: Default::default(),
+            hostname: Arc::new("relay.example".to_string()),
+            priv_key: Arc::new(priv_key),
+            pub_key: Arc::new(pub_key),
+            blocklist: Default::default(),
+            approve_follows: false,
+            stream_statuses: StreamStatuses::default(),
+        }
+    }
+
+    async fn follows(state: &State, uri: &str) -> Vec<Follow> {
+        let uri = uri.parse().unwrap();
+        let response = get_follows(
+            AxumState(state.clone()),
+            Query::try_from_uri(&uri).unwrap(),
+            Query::try_from_uri(&uri).unwrap(),
+        ).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn follows_page() {
+        let state = state().await;
+        for id in ["a", "b", "c"] {
+            state.database.add_follow(&format!("https://{id}.example/u"), &format!("https://{id}.example/inbox"), "relay/tag/rust", false, &Filter::default()).await.unwrap();
+        }
+        state.database.add_follow("https://a.example/u", "https://a.example/inbox", "relay/tag/go", false, &Filter::default()).await.unwrap();
+
+        assert_eq!(follows(&state, "/follows").await.len(), 4);
+        let page = follows(&state, "/follows?actor=relay/tag/rust&limit=1&offset=1").await;
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, "https://b.example/u");
+        assert_eq!(follows(&state, "/follows?limit=10&offset=3").await.len(), 1);
+    }
+}
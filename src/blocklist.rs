@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use serde::Deserialize;
+use crate::config::BlocklistConfig;
+
+/// How often to check blocklist files for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Blocked domains. Every entry also blocks all of its subdomains,
+/// whether it is written as `example.com` or `*.example.com`.
+#[derive(Clone, Default)]
+pub struct Blocklist {
+    /// from the config and its files
+    domains: Arc<RwLock<HashSet<String>>>,
+    /// added through the admin API, not touched by reloading
+    dynamic: Arc<RwLock<HashSet<String>>>,
+}
+
+/// Lowercases and strips any wildcard prefix
+pub fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim()
+        .trim_start_matches("*.")
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_lowercase();
+    if domain.is_empty() || domain.contains('*') {
+        None
+    } else {
+        Some(domain)
+    }
+}
+
+/// Normalizes a blocklist entry, skipping domains that were
+/// obfuscated like `ba*.example` as they can never match
+fn entry(domain: &str) -> Option<String> {
+    let normalized = normalize(domain);
+    if normalized.is_none() && domain.contains('*') {
+        tracing::warn!("skipping obfuscated blocklist entry {}", domain.trim());
+    }
+    normalized
+}
+
+/// Whether a Mastodon domain block severity means we should not relay
+fn is_blocking_severity(severity: &str) -> bool {
+    matches!(severity.trim(), "" | "suspend" | "silence")
+}
+
+/// Parses one domain per line, ignoring empty lines and `#` comments
+fn parse_list(data: &str) -> impl Iterator<Item = String> + '_ {
+    data.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .filter_map(entry)
+}
+
+/// Parses Mastodon's domain block export (`#domain,#severity,...`),
+/// and the CSV written by FediBlockHole and consensus blocklists
+/// (`domain,severity,...`), with or without a header line. Entries
+/// with severity `noop` are skipped.
+fn parse_csv(data: &str) -> Result<Vec<String>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(data.as_bytes());
+    let mut records = reader.records();
+    let Some(header) = records.next().transpose()? else {
+        return Ok(vec![]);
+    };
+    let column = |name: &str| header.iter()
+        .position(|field| field.trim().trim_start_matches('#') == name);
+    let mut domains = vec![];
+    let mut add = |record: &csv::StringRecord, domain_column: usize, severity_column: Option<usize>| {
+        let severity = severity_column
+            .and_then(|severity_column| record.get(severity_column))
+            .unwrap_or("");
+        if ! is_blocking_severity(severity) {
+            return;
+        }
+        if let Some(domain) = record.get(domain_column).and_then(entry) {
+            domains.push(domain);
+        }
+    };
+    let (domain_column, severity_column) = match column("domain") {
+        Some(domain_column) =>
+            (domain_column, column("severity")),
+        None => {
+            // no header, in the order of the Mastodon export
+            add(&header, 0, Some(1));
+            (0, Some(1))
+        }
+    };
+
+    for record in records {
+        add(&record?, domain_column, severity_column);
+    }
+    Ok(domains)
+}
+
+/// An entry of the Mastodon API's and FediBlockHole's JSON format
+#[derive(Deserialize)]
+struct JsonBlock {
+    domain: String,
+    #[serde(default)]
+    severity: String,
+}
+
+fn parse_json(data: &str) -> Result<Vec<String>, serde_json::Error> {
+    let blocks: Vec<JsonBlock> = serde_json::from_str(data)?;
+    Ok(blocks.into_iter()
+       .filter(|block| is_blocking_severity(&block.severity))
+       .filter_map(|block| entry(&block.domain))
+       .collect())
+}
+
+/// Detects the format of a blocklist file by its content
+fn parse_file(data: &str) -> Result<Vec<String>, String> {
+    let first_line = data.lines()
+        .map(str::trim)
+        .find(|line| ! line.is_empty())
+        .unwrap_or("");
+    if first_line.starts_with('[') {
+        parse_json(data)
+            .map_err(|e| format!("{e}"))
+    } else if first_line.contains(',') {
+        parse_csv(data)
+            .map_err(|e| format!("{e}"))
+    } else {
+        Ok(parse_list(data).collect())
+    }
+}
+
+fn read(config: &BlocklistConfig) -> Result<HashSet<String>, String> {
+    let mut domains = config.domains.iter()
+        .filter_map(|domain| entry(domain))
+        .collect::<HashSet<_>>();
+    for file in &config.files {
+        let data = std::fs::read_to_string(file)
+            .map_err(|e| format!("{file}: {e}"))?;
+        domains.extend(
+            parse_file(&data)
+                .map_err(|e| format!("{file}: {e}"))?
+        );
+    }
+    Ok(domains)
+}
+
+fn modification_times(config: &BlocklistConfig) -> Vec<Option<SystemTime>> {
+    config.files.iter()
+        .map(|file| std::fs::metadata(file)
+             .and_then(|metadata| metadata.modified())
+             .ok()
+        )
+        .collect()
+}
+
+impl Blocklist {
+    pub fn load(config: &BlocklistConfig) -> Self {
+        let domains = read(config)
+            .expect("read blocklist");
+        tracing::info!("loaded {} blocked domains", domains.len());
+
+        Blocklist {
+            domains: Arc::new(RwLock::new(domains)),
+            dynamic: Arc::default(),
+        }
+    }
+
+    /// Blocks an already normalized domain in addition to the config
+    pub fn add(&self, domain: String) {
+        self.dynamic.write().unwrap().insert(domain);
+    }
+
+    /// Lifts a block that was added with [`Blocklist::add`]
+    pub fn remove(&self, domain: &str) {
+        self.dynamic.write().unwrap().remove(domain);
+    }
+
+    /// Reloads the blocklist whenever one of its files changes
+    pub fn spawn_reload(&self, config: BlocklistConfig) {
+        if config.files.is_empty() {
+            return;
+        }
+
+        let blocklist = self.clone();
+        tokio::spawn(async move {
+            let mut mtimes = modification_times(&config);
+            loop {
+                tokio::time::sleep(RELOAD_INTERVAL).await;
+
+                let new_mtimes = modification_times(&config);
+                if new_mtimes == mtimes {
+                    continue;
+                }
+                mtimes = new_mtimes;
+
+                match read(&config) {
+                    Ok(domains) => {
+                        tracing::info!("reloaded {} blocked domains", domains.len());
+                        *blocklist.domains.write().unwrap() = domains;
+                    }
+                    Err(e) =>
+                        tracing::error!("reload blocklist: {}", e),
+                }
+            }
+        });
+    }
+
+    pub fn is_blocked(&self, host: &str) -> bool {
+        let Some(host) = normalize(host) else {
+            return false;
+        };
+        let domains = self.domains.read().unwrap();
+        let dynamic = self.dynamic.read().unwrap();
+        let mut suffix = host.as_str();
+        loop {
+            if domains.contains(suffix) || dynamic.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blocklist(domains: &[&str]) -> Blocklist {
+        Blocklist::load(&BlocklistConfig {
+            domains: domains.iter().map(ToString::to_string).collect(),
+            files: vec![],
+        })
+    }
+
+    #[test]
+    fn subdomains() {
+        let blocklist = blocklist(&["bad.example", "*.worse.example"]);
+        assert!(blocklist.is_blocked("bad.example"));
+        assert!(blocklist.is_blocked("BAD.example."));
+        assert!(blocklist.is_blocked("social.bad.example"));
+        assert!(blocklist.is_blocked("worse.example"));
+        assert!(blocklist.is_blocked("a.b.worse.example"));
+        assert!(! blocklist.is_blocked("notbad.example"));
+        assert!(! blocklist.is_blocked("example"));
+        assert!(! blocklist.is_blocked(""));
+    }
+
+    #[test]
+    fn dynamic() {
+        let blocklist = blocklist(&["bad.example"]);
+        blocklist.add("worse.example".to_string());
+        assert!(blocklist.is_blocked("bad.example"));
+        assert!(blocklist.is_blocked("social.worse.example"));
+        blocklist.remove("worse.example");
+        assert!(! blocklist.is_blocked("social.worse.example"));
+    }
+
+    #[test]
+    fn list() {
+        let domains = parse_file("# comment\nbad.example\n\n *.worse.example # spam\nw*rst.example\n")
+            .unwrap();
+        assert_eq!(domains, vec!["bad.example", "worse.example"]);
+    }
+
+    #[test]
+    fn mastodon_csv() {
+        let domains = parse_file("\
+#domain,#severity,#reject_media,#reject_reports,#public_comment,#obfuscate
+bad.example,suspend,true,true,\"spam, harassment\",false
+meh.example,noop,true,false,,false
+limited.example,silence,false,false,,false
+").unwrap();
+        assert_eq!(domains, vec!["bad.example", "limited.example"]);
+    }
+
+    #[test]
+    fn fediblockhole_csv() {
+        let domains = parse_file("\
+domain,severity,public_comment
+bad.example,suspend,spam
+meh.example,noop,
+").unwrap();
+        assert_eq!(domains, vec!["bad.example"]);
+    }
+
+    #[test]
+    fn headerless_csv() {
+        let domains = parse_file("bad.example,suspend\nmeh.example,noop\nworse.example,suspend\nba*.example,suspend\nsome.example\n")
+            .unwrap();
+        assert_eq!(domains, vec!["bad.example", "worse.example", "some.example"]);
+        let domains = parse_file("meh.example,noop\nbad.example,suspend\n")
+            .unwrap();
+        assert_eq!(domains, vec!["bad.example"]);
+    }
+
+    #[test]
+    fn json() {
+        let domains = parse_file(r#"[
+            {"domain": "bad.example", "severity": "suspend", "comment": "spam"},
+            {"domain": "meh.example", "severity": "noop"}
+        ]"#).unwrap();
+        assert_eq!(domains, vec!["bad.example"]);
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::{Read, Write};
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::{config::Config, db::{Database, Follow}};
+
+/// Page size when reading all follows
+const EXPORT_BATCH: i64 = 1000;
+
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Serve with this config file, same as `serve --config`
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the relay
+    Serve {
+        #[arg(short, long, default_value = "config.yaml")]
+        config: String,
+    },
+    /// Inspect and edit follows
+    Follows {
+        #[arg(short, long, default_value = "config.yaml")]
+        config: String,
+        #[command(subcommand)]
+        command: FollowsCommand,
+    },
+    /// Database maintenance
+    Db {
+        #[arg(short, long, default_value = "config.yaml")]
+        config: String,
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Signing keypair
+    Keys {
+        #[arg(short, long, default_value = "config.yaml")]
+        config: String,
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum FollowsCommand {
+    /// Print follows as `actor id inbox`
+    List {
+        /// Only the followers of this actor
+        #[arg(long)]
+        actor: Option<String>,
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+    },
+    /// Remove a follow without notifying the follower
+    Remove {
+        /// The follower's actor id
+        id: String,
+        /// The followed relay actor
+        actor: String,
+    },
+    /// Write all follows to a file, or to stdout
+    Export {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        output: Option<String>,
+    },
+    /// Add follows from a file, or from stdin, skipping existing ones
+    Import {
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        input: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Bring the database schema up to date
+    Migrate,
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// Create `priv_key_file` and `pub_key_file`
+    Generate {
+        /// Replace existing key files
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Cli {
+    /// Without a subcommand, serve with the legacy positional config
+    pub fn command(self) -> Command {
+        self.command.unwrap_or_else(|| Command::Serve {
+            config: self.config
+                .unwrap_or_else(|| "config.yaml".to_string()),
+        })
+    }
+}
+
+pub async fn follows(config: &Config, command: FollowsCommand) {
+    let database = Database::open(&config.db).await;
+    match command {
+        FollowsCommand::List { actor, limit, offset } => {
+            let follows = database.get_follows(actor.as_deref(), limit, offset).await
+                .expect("get_follows");
+            for follow in follows {
+                println!("{} {} {}", follow.actor, follow.id, follow.inbox);
+            }
+        }
+        FollowsCommand::Remove { id, actor } => {
+            database.del_follow(&id, &actor).await
+                .expect("del_follow");
+        }
+        FollowsCommand::Export { format, output } => {
+            let mut follows = vec![];
+            loop {
+                let batch = database.get_follows(None, EXPORT_BATCH, follows.len() as i64).await
+                    .expect("get_follows");
+                let done = (batch.len() as i64) < EXPORT_BATCH;
+                follows.extend(batch);
+                if done {
+                    break;
+                }
+            }
+            let output: Box<dyn Write> = match output {
+                Some(output) => Box::new(
+                    std::fs::File::create(output)
+                        .expect("create output")
+                ),
+                None => Box::new(std::io::stdout()),
+            };
+            write_follows(format, output, &follows)
+                .expect("write follows");
+            eprintln!("exported {} follows", follows.len());
+        }
+        FollowsCommand::Import { format, input } => {
+            let mut data = String::new();
+            match input {
+                Some(input) => std::fs::File::open(input)
+                    .and_then(|mut file| file.read_to_string(&mut data)),
+                None => std::io::stdin().read_to_string(&mut data),
+            }.expect("read input");
+            let follows = read_follows(format, &data)
+                .expect("parse follows");
+            let mut added = 0;
+            for follow in &follows {
+                if database.import_follow(follow).await.expect("import_follow") {
+                    added += 1;
+                }
+            }
+            eprintln!("imported {} of {} follows", added, follows.len());
+        }
+    }
+}
+
+pub async fn db(config: &Config, command: DbCommand) {
+    match command {
+        DbCommand::Migrate => {
+            let applied = Database::migrate(&config.db).await;
+            eprintln!("applied {} migrations, database schema is up to date", applied);
+        }
+    }
+}
+
+pub fn keys(config: &Config, command: KeysCommand) {
+    match command {
+        KeysCommand::Generate { force } => {
+            config.generate_keys(force)
+                .expect("generate keys");
+            eprintln!("generated signing keypair");
+        }
+    }
+}
+
+fn write_follows(format: Format, mut output: impl Write, follows: &[Follow]) -> Result<(), String> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut output, follows)
+                .map_err(|e| format!("{e}"))?;
+            writeln!(output)
+                .map_err(|e| format!("{e}"))
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            for follow in follows {
+                writer.serialize(follow)
+                    .map_err(|e| format!("{e}"))?;
+            }
+            writer.flush()
+                .map_err(|e| format!("{e}"))
+        }
+    }
+}
+
+fn read_follows(format: Format, data: &str) -> Result<Vec<Follow>, String> {
+    match format {
+        Format::Json =>
+            serde_json::from_str(data)
+            .map_err(|e| format!("{e}")),
+        Format::Csv =>
+            csv::Reader::from_reader(data.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("{e}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn follows() -> Vec<Follow> {
+        vec![Follow {
+            id: "https://example.com/users/a".to_string(),
+            inbox: "https://example.com/inbox".to_string(),
+            actor: "https://relay.fedi.buzz/tag/rust".to_string(),
+            created_at: Some(1700000000),
+            last_delivered_at: None,
+            software_name: Some("mastodon".to_string()),
+            software_version: Some("4.3.0".to_string()),
+            shared_inbox: true,
+            filter: "languages=en,de&media_only=true".parse().unwrap(),
+        }]
+    }
+
+    #[test]
+    fn roundtrip() {
+        for format in [Format::Json, Format::Csv] {
+            let mut data = vec![];
+            write_follows(format, &mut data, &follows()).unwrap();
+            let read = read_follows(format, std::str::from_utf8(&data).unwrap()).unwrap();
+            assert_eq!(read.len(), 1);
+            assert_eq!(read[0].id, "https://example.com/users/a");
+            assert_eq!(read[0].inbox, "https://example.com/inbox");
+            assert_eq!(read[0].actor, "https://relay.fedi.buzz/tag/rust");
+            assert_eq!(read[0].created_at, Some(1700000000));
+            assert_eq!(read[0].last_delivered_at, None);
+            assert_eq!(read[0].software_name.as_deref(), Some("mastodon"));
+            assert!(read[0].shared_inbox);
+            assert_eq!(read[0].filter, follows()[0].filter);
+        }
+    }
+
+    #[test]
+    fn without_metadata() {
+        let read = read_follows(Format::Csv, "\
+id,inbox,actor
+https://example.com/users/a,https://example.com/inbox,https://relay.fedi.buzz/tag/rust
+").unwrap();
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].created_at, None);
+        assert!(! read[0].shared_inbox);
+        assert!(read[0].filter.is_empty());
+    }
+
+    #[test]
+    fn legacy_config_argument() {
+        let cli = Cli::try_parse_from(["buzzrelay", "relay.yaml"]).unwrap();
+        assert!(matches!(cli.command(), Command::Serve { config } if config == "relay.yaml"));
+        let cli = Cli::try_parse_from(["buzzrelay", "follows", "-c", "relay.yaml", "list"]).unwrap();
+        assert!(matches!(cli.command(), Command::Follows { config, command: FollowsCommand::List { .. } } if config == "relay.yaml"));
+    }
+}
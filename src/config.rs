@@ -1,5 +1,45 @@
+use std::{collections::HashMap, io::Write, os::unix::fs::OpenOptionsExt};
 use serde::Deserialize;
-use sigh::{PrivateKey, PublicKey, Key};
+use sigh::{alg::{Algorithm, RsaSha256}, PrivateKey, PublicKey, Key};
+
+/// A source stream, either just its URL or with more options
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum StreamConfig {
+    Url(String),
+    Detailed(DetailedStreamConfig),
+}
+
+#[derive(Clone, Deserialize)]
+pub struct DetailedStreamConfig {
+    pub url: String,
+    /// Sent as bearer token instead of putting it into the URL
+    pub access_token_file: Option<String>,
+    /// Defaults to the protocol implied by the URL scheme
+    #[serde(rename = "type")]
+    pub stream_type: Option<StreamType>,
+    /// WebSocket streams: `public`, `public:local`, `hashtag:<tag>`
+    #[serde(default)]
+    pub subscriptions: Vec<String>,
+    #[serde(default)]
+    pub filter: StreamFilter,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamType {
+    Sse,
+    WebSocket,
+}
+
+/// Only pass posts of a stream that match all of the given criteria
+#[derive(Clone, Default, Deserialize)]
+pub struct StreamFilter {
+    /// Any of these languages
+    pub languages: Option<Vec<String>>,
+    /// Any of these hashtags
+    pub tags: Option<Vec<String>>,
+}
 
 #[derive(Clone, Deserialize)]
 pub struct RedisConfig {
@@ -8,13 +48,108 @@ pub struct RedisConfig {
     pub in_topic: String,
 }
 
+/// Unfollow inboxes that have been failing for a long time
+#[derive(Clone, Deserialize)]
+pub struct PruneConfig {
+    /// Minimum number of consecutive failed deliveries
+    pub failures: u32,
+    /// Minimum number of days since the first of these failures
+    pub days: u32,
+}
+
+/// Limits for delivering to one remote host
+#[derive(Clone, Copy, Deserialize)]
+pub struct HostLimits {
+    /// Parallel in-flight requests
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Maximum requests per second
+    #[serde(default)]
+    pub rate: Option<f64>,
+    /// Jobs that may be queued before new ones are left in the
+    /// database, to be replayed later
+    #[serde(default = "default_queue")]
+    pub queue: usize,
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_queue() -> usize {
+    512
+}
+
+impl Default for HostLimits {
+    fn default() -> Self {
+        HostLimits {
+            concurrency: default_concurrency(),
+            rate: None,
+            queue: default_queue(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct DeliveryConfig {
+    /// Limits for any host not listed in `hosts`
+    #[serde(flatten)]
+    pub default: HostLimits,
+    /// Per-host overrides
+    #[serde(default)]
+    pub hosts: HashMap<String, HostLimits>,
+}
+
+impl DeliveryConfig {
+    pub fn limits(&self, host: &str) -> HostLimits {
+        self.hosts.get(host)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Domains to ignore posts and follows from
+#[derive(Clone, Default, Deserialize)]
+pub struct BlocklistConfig {
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Files in plain text, CSV or JSON format
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+/// The admin API under `/admin`
+#[derive(Clone, Deserialize)]
+pub struct AdminConfig {
+    /// Contains the bearer token that authorizes requests
+    pub token_file: String,
+}
+
+impl AdminConfig {
+    pub fn token(&self) -> String {
+        std::fs::read_to_string(&self.token_file)
+            .expect("read admin token_file")
+            .trim()
+            .to_string()
+    }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Config {
-    pub streams: Vec<String>,
+    pub streams: Vec<StreamConfig>,
     pub db: String,
     pub hostname: String,
     pub listen_port: u16,
     pub redis: Option<RedisConfig>,
+    pub prune_inboxes: Option<PruneConfig>,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+    #[serde(default)]
+    pub blocklist: BlocklistConfig,
+    /// Hold new follows until an admin approves them
+    #[serde(default)]
+    pub approve_follows: bool,
+    pub admin: Option<AdminConfig>,
     priv_key_file: String,
     pub_key_file: String,
 }
@@ -40,4 +175,54 @@ impl Config {
         PublicKey::from_pem(data.as_bytes())
             .expect("pub_key")
     }
+
+    /// Writes a new RSA keypair to `priv_key_file` and `pub_key_file`
+    pub fn generate_keys(&self, overwrite: bool) -> std::io::Result<()> {
+        if ! overwrite {
+            // don't leave a mismatched pair behind
+            for file in [&self.priv_key_file, &self.pub_key_file] {
+                if std::path::Path::new(file).exists() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("{file} exists"),
+                    ));
+                }
+            }
+        }
+        let (priv_key, pub_key) = RsaSha256.generate_keys()
+            .map_err(std::io::Error::other)?;
+        let priv_pem = priv_key.to_pem()
+            .map_err(std::io::Error::other)?;
+        let pub_pem = pub_key.to_pem()
+            .map_err(std::io::Error::other)?;
+        for (file, pem, mode) in [(&self.priv_key_file, priv_pem, 0o600), (&self.pub_key_file, pub_pem, 0o644)] {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).mode(mode);
+            if overwrite {
+                options.create(true).truncate(true);
+            } else {
+                options.create_new(true);
+            }
+            let mut file = options.open(file)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{file}: {e}")))?;
+            file.write_all(pem.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn example_config() {
+        let config: Config = serde_yaml::from_str(include_str!("../config.yaml"))
+            .unwrap();
+        assert_eq!(config.streams.len(), 4);
+        assert!(matches!(config.streams[0], StreamConfig::Url(_)));
+        assert!(matches!(config.streams[3], StreamConfig::Detailed(_)));
+        // would need a token file
+        assert!(config.admin.is_none());
+    }
 }
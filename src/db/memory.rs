@@ -0,0 +1,401 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use async_trait::async_trait;
+use crate::{filter::Filter, subscriptions::Subscriber};
+use super::{Announcement, Delivery, Error, Follow, InboxHealth, PendingFollow, Storage};
+
+/// Seconds since the Unix epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn subscriber(follow: &Follow) -> Subscriber {
+    Subscriber {
+        inbox: follow.inbox.clone(),
+        filter: follow.filter.clone(),
+    }
+}
+
+struct StoredDelivery {
+    id: i64,
+    inbox: String,
+    actor: String,
+    post_url: String,
+    body_id: i64,
+    attempts: i32,
+}
+
+struct StoredAnnouncement {
+    stream: String,
+    status_id: String,
+    created_at: i64,
+    announcement: Announcement,
+}
+
+#[derive(Default)]
+struct State {
+    follows: Vec<Follow>,
+    next_delivery_id: i64,
+    deliveries: Vec<StoredDelivery>,
+    next_body_id: i64,
+    bodies: HashMap<i64, Vec<u8>>,
+    inbox_health: HashMap<String, InboxHealth>,
+    announcements: Vec<StoredAnnouncement>,
+    pending_follows: Vec<PendingFollow>,
+    domain_blocks: BTreeSet<String>,
+}
+
+/// Kept in process memory only, for tests and for relays that can
+/// afford to lose their followers on restart
+#[derive(Default)]
+pub struct Memory {
+    state: Mutex<State>,
+}
+
+impl Memory {
+    fn with<T>(&self, f: impl FnOnce(&mut State) -> T) -> Result<T, Error> {
+        Ok(f(&mut self.state.lock().unwrap()))
+    }
+}
+
+#[async_trait]
+impl Storage for Memory {
+    async fn migrate(&self) -> Result<usize, Error> {
+        Ok(0)
+    }
+
+    async fn add_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, filter: &Filter) -> Result<(), Error> {
+        self.with(|state| {
+            if let Some(follow) = state.follows.iter_mut().find(|follow| follow.id == id && follow.actor == actor) {
+                follow.inbox = inbox.to_string();
+                follow.shared_inbox = shared_inbox;
+                follow.filter = filter.clone();
+                return;
+            }
+            state.follows.push(Follow {
+                id: id.to_string(),
+                inbox: inbox.to_string(),
+                actor: actor.to_string(),
+                created_at: Some(now()),
+                last_delivered_at: None,
+                software_name: None,
+                software_version: None,
+                shared_inbox,
+                filter: filter.clone(),
+            });
+        })
+    }
+
+    async fn del_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        self.with(|state| {
+            state.follows.retain(|follow| follow.id != id || follow.actor != actor);
+        })
+    }
+
+    async fn get_subscriptions(&self) -> Result<Vec<(String, Subscriber)>, Error> {
+        self.with(|state| {
+            state.follows.iter()
+                .map(|follow| (follow.actor.clone(), subscriber(follow)))
+                .collect()
+        })
+    }
+
+    async fn get_subscribers(&self, actor: &str) -> Result<Vec<Subscriber>, Error> {
+        self.with(|state| {
+            state.follows.iter()
+                .filter(|follow| follow.actor == actor)
+                .map(subscriber)
+                .collect()
+        })
+    }
+
+    async fn get_follows_count(&self) -> Result<i64, Error> {
+        self.with(|state| state.follows.len() as i64)
+    }
+
+    async fn get_followers_count(&self) -> Result<i64, Error> {
+        self.with(|state| {
+            state.follows.iter()
+                .map(|follow| &follow.id)
+                .collect::<BTreeSet<_>>()
+                .len() as i64
+        })
+    }
+
+    async fn get_follows(&self, actor: Option<&str>, limit: i64, offset: i64) -> Result<Vec<Follow>, Error> {
+        self.with(|state| {
+            let mut follows = state.follows.iter()
+                .filter(|follow| actor.is_none_or(|actor| follow.actor == actor))
+                .cloned()
+                .collect::<Vec<_>>();
+            follows.sort_by(|a, b| (&a.actor, &a.id).cmp(&(&b.actor, &b.id)));
+            follows.into_iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .collect()
+        })
+    }
+
+    async fn import_follow(&self, follow: &Follow) -> Result<bool, Error> {
+        self.with(|state| {
+            if state.follows.iter().any(|existing| existing.id == follow.id && existing.actor == follow.actor) {
+                return false;
+            }
+            let mut follow = follow.clone();
+            follow.created_at.get_or_insert_with(now);
+            state.follows.push(follow);
+            true
+        })
+    }
+
+    async fn set_follow_software(&self, id: &str, name: &str, version: Option<&str>) -> Result<(), Error> {
+        self.with(|state| {
+            for follow in state.follows.iter_mut().filter(|follow| follow.id == id) {
+                follow.software_name = Some(name.to_string());
+                follow.software_version = version.map(str::to_string);
+            }
+        })
+    }
+
+    async fn set_follow_filter(&self, id: &str, actor: &str, filter: &Filter) -> Result<bool, Error> {
+        self.with(|state| {
+            let mut found = false;
+            for follow in state.follows.iter_mut().filter(|follow| follow.id == id && follow.actor == actor) {
+                follow.filter = filter.clone();
+                found = true;
+            }
+            found
+        })
+    }
+
+    async fn add_deliveries(&self, inboxes: &[String], actor: &str, post_url: &str, body: &[u8]) -> Result<Vec<i64>, Error> {
+        self.with(|state| {
+            state.next_body_id += 1;
+            let body_id = state.next_body_id;
+            state.bodies.insert(body_id, body.to_vec());
+            inboxes.iter()
+                .map(|inbox| {
+                    state.next_delivery_id += 1;
+                    let id = state.next_delivery_id;
+                    state.deliveries.push(StoredDelivery {
+                        id,
+                        inbox: inbox.to_string(),
+                        actor: actor.to_string(),
+                        post_url: post_url.to_string(),
+                        body_id,
+                        attempts: 0,
+                    });
+                    id
+                })
+                .collect()
+        })
+    }
+
+    async fn del_delivery(&self, id: i64) -> Result<(), Error> {
+        self.with(|state| {
+            let Some(index) = state.deliveries.iter().position(|delivery| delivery.id == id) else {
+                return;
+            };
+            let body_id = state.deliveries.remove(index).body_id;
+            if ! state.deliveries.iter().any(|delivery| delivery.body_id == body_id) {
+                state.bodies.remove(&body_id);
+            }
+        })
+    }
+
+    async fn retry_delivery(&self, id: i64, attempts: i32) -> Result<(), Error> {
+        self.with(|state| {
+            if let Some(delivery) = state.deliveries.iter_mut().find(|delivery| delivery.id == id) {
+                delivery.attempts = attempts;
+            }
+        })
+    }
+
+    async fn get_deliveries(&self, after: i64, limit: i64) -> Result<Vec<Delivery>, Error> {
+        self.with(|state| {
+            state.deliveries.iter()
+                .filter(|delivery| delivery.id > after)
+                .take(limit as usize)
+                .map(|delivery| Delivery {
+                    id: delivery.id,
+                    inbox: delivery.inbox.clone(),
+                    actor: delivery.actor.clone(),
+                    post_url: delivery.post_url.clone(),
+                    body: state.bodies.get(&delivery.body_id).cloned().unwrap_or_default(),
+                    attempts: delivery.attempts,
+                })
+                .collect()
+        })
+    }
+
+    async fn prune_delivery_bodies(&self) -> Result<u64, Error> {
+        // bodies go with their last delivery
+        Ok(0)
+    }
+
+    async fn record_delivery_success(&self, inbox: &str) -> Result<(), Error> {
+        self.with(|state| {
+            let now = now();
+            state.inbox_health.insert(inbox.to_string(), InboxHealth {
+                inbox: inbox.to_string(),
+                failures: 0,
+                failing_since: None,
+                last_success: Some(now),
+            });
+            for follow in state.follows.iter_mut().filter(|follow| follow.inbox == inbox) {
+                follow.last_delivered_at = Some(now);
+            }
+        })
+    }
+
+    async fn record_delivery_failure(&self, inbox: &str) -> Result<(), Error> {
+        self.with(|state| {
+            let health = state.inbox_health.entry(inbox.to_string())
+                .or_insert_with(|| InboxHealth {
+                    inbox: inbox.to_string(),
+                    failures: 0,
+                    failing_since: None,
+                    last_success: None,
+                });
+            health.failures += 1;
+            health.failing_since.get_or_insert_with(now);
+        })
+    }
+
+    async fn prune_dead_inboxes(&self, failures: i32, failing_for: Duration) -> Result<Vec<(String, String, String)>, Error> {
+        self.with(|state| {
+            let deadline = now() - failing_for.as_secs() as i64;
+            let mut dead = BTreeSet::new();
+            state.inbox_health.retain(|inbox, health| {
+                let alive = health.failures < failures
+                    || health.failing_since.is_none_or(|since| since >= deadline);
+                if ! alive {
+                    dead.insert(inbox.clone());
+                }
+                alive
+            });
+            let (removed, kept): (Vec<_>, _) = state.follows.drain(..)
+                .partition(|follow| dead.contains(&follow.inbox));
+            state.follows = kept;
+            removed.into_iter()
+                .map(|follow| (follow.id, follow.inbox, follow.actor))
+                .collect()
+        })
+    }
+
+    async fn get_inbox_health(&self, limit: i64, offset: i64) -> Result<Vec<InboxHealth>, Error> {
+        self.with(|state| {
+            let mut health = state.inbox_health.values()
+                .cloned()
+                .collect::<Vec<_>>();
+            health.sort_by(|a, b| b.failures.cmp(&a.failures).then_with(|| a.inbox.cmp(&b.inbox)));
+            health.into_iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .collect()
+        })
+    }
+
+    async fn add_announcement(&self, stream: &str, status_id: &str, announcement: &Announcement) -> Result<(), Error> {
+        self.with(|state| {
+            state.announcements.push(StoredAnnouncement {
+                stream: stream.to_string(),
+                status_id: status_id.to_string(),
+                created_at: now(),
+                announcement: announcement.clone(),
+            });
+        })
+    }
+
+    async fn get_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        self.with(|state| {
+            state.announcements.iter()
+                .filter(|stored| stored.stream == stream && stored.status_id == status_id)
+                .map(|stored| stored.announcement.clone())
+                .collect()
+        })
+    }
+
+    async fn take_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        self.with(|state| {
+            let (taken, kept): (Vec<_>, _) = state.announcements.drain(..)
+                .partition(|stored| stored.stream == stream && stored.status_id == status_id);
+            state.announcements = kept;
+            taken.into_iter()
+                .map(|stored| stored.announcement)
+                .collect()
+        })
+    }
+
+    async fn prune_announcements(&self, max_age: Duration) -> Result<u64, Error> {
+        self.with(|state| {
+            let deadline = now() - max_age.as_secs() as i64;
+            let before = state.announcements.len();
+            state.announcements.retain(|stored| stored.created_at >= deadline);
+            (before - state.announcements.len()) as u64
+        })
+    }
+
+    async fn add_pending_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, follow: &str, filter: &Filter) -> Result<(), Error> {
+        self.with(|state| {
+            match state.pending_follows.iter_mut().find(|pending| pending.id == id && pending.actor == actor) {
+                Some(pending) => {
+                    pending.inbox = inbox.to_string();
+                    pending.shared_inbox = shared_inbox;
+                    pending.follow = follow.to_string();
+                    pending.filter = filter.clone();
+                }
+                None =>
+                    state.pending_follows.push(PendingFollow {
+                        id: id.to_string(),
+                        inbox: inbox.to_string(),
+                        actor: actor.to_string(),
+                        shared_inbox,
+                        follow: follow.to_string(),
+                        filter: filter.clone(),
+                        created_at: now(),
+                    }),
+            }
+        })
+    }
+
+    async fn get_pending_follows(&self) -> Result<Vec<PendingFollow>, Error> {
+        self.with(|state| state.pending_follows.clone())
+    }
+
+    async fn get_pending_follow(&self, id: &str, actor: &str) -> Result<Option<PendingFollow>, Error> {
+        self.with(|state| {
+            state.pending_follows.iter()
+                .find(|pending| pending.id == id && pending.actor == actor)
+                .cloned()
+        })
+    }
+
+    async fn del_pending_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        self.with(|state| {
+            state.pending_follows.retain(|pending| pending.id != id || pending.actor != actor);
+        })
+    }
+
+    async fn add_domain_block(&self, domain: &str) -> Result<(), Error> {
+        self.with(|state| {
+            state.domain_blocks.insert(domain.to_string());
+        })
+    }
+
+    async fn del_domain_block(&self, domain: &str) -> Result<(), Error> {
+        self.with(|state| {
+            state.domain_blocks.remove(domain);
+        })
+    }
+
+    async fn get_domain_blocks(&self) -> Result<Vec<String>, Error> {
+        self.with(|state| state.domain_blocks.iter().cloned().collect())
+    }
+}
@@ -0,0 +1,681 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use async_trait::async_trait;
+use futures::{channel::mpsc::UnboundedReceiver, StreamExt};
+use serde::{Deserialize, Serialize};
+use crate::{
+    actor::Actor,
+    filter::Filter,
+    keywords::Keywords,
+    subscriptions::{Subscriber, Subscriptions},
+};
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("PostgreSQL: {0}")]
+    Postgres(#[from] deadpool_postgres::PoolError),
+    #[error("SQLite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("database task: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl From<tokio_postgres::Error> for Error {
+    fn from(e: tokio_postgres::Error) -> Self {
+        Error::Postgres(e.into())
+    }
+}
+
+/// A queued outbound activity that has not been acknowledged yet
+#[derive(Clone)]
+pub struct Delivery {
+    pub id: i64,
+    pub inbox: String,
+    pub actor: String,
+    pub post_url: String,
+    pub body: Vec<u8>,
+    pub attempts: i32,
+}
+
+/// A post that an actor has announced to a set of inboxes
+#[derive(Clone)]
+pub struct Announcement {
+    pub post_url: String,
+    pub uri: String,
+    pub actor: String,
+    pub inboxes: Vec<String>,
+}
+
+/// A `Follow` that awaits approval by an admin
+#[derive(Clone)]
+pub struct PendingFollow {
+    pub id: String,
+    pub inbox: String,
+    pub actor: String,
+    /// Whether `inbox` is the follower's `sharedInbox`
+    pub shared_inbox: bool,
+    /// The original activity, to be included in the response
+    pub follow: String,
+    pub filter: Filter,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// A row of `follows`, timestamps in seconds since the Unix epoch
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Follow {
+    pub id: String,
+    pub inbox: String,
+    pub actor: String,
+    /// Unknown for follows from before it was recorded
+    #[serde(default)]
+    pub created_at: Option<i64>,
+    /// Updated at most hourly
+    #[serde(default)]
+    pub last_delivered_at: Option<i64>,
+    /// From the nodeinfo of the follower's instance
+    #[serde(default)]
+    pub software_name: Option<String>,
+    #[serde(default)]
+    pub software_version: Option<String>,
+    /// Whether `inbox` is the follower's `sharedInbox`
+    #[serde(default)]
+    pub shared_inbox: bool,
+    #[serde(default)]
+    pub filter: Filter,
+}
+
+/// Delivery health of an inbox, timestamps in seconds since the Unix epoch
+#[derive(Clone)]
+pub struct InboxHealth {
+    pub inbox: String,
+    pub failures: i32,
+    pub failing_since: Option<i64>,
+    pub last_success: Option<i64>,
+}
+
+/// Reported by backends that can be changed by other processes
+pub enum FollowsChanged {
+    /// Anything may have changed
+    All,
+    /// The follows of one actor
+    Actor(String),
+}
+
+/// Everything the relay persists
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Brings the schema up to date, returning the number of applied
+    /// migrations
+    async fn migrate(&self) -> Result<usize, Error>;
+    fn watch_follows(&self) -> Option<UnboundedReceiver<FollowsChanged>> {
+        None
+    }
+
+    /// Replaces any follow by the same `id` through another inbox,
+    /// updates the filter of an existing one
+    async fn add_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, filter: &Filter) -> Result<(), Error>;
+    async fn del_follow(&self, id: &str, actor: &str) -> Result<(), Error>;
+    /// All follows as `(actor, subscriber)` pairs
+    async fn get_subscriptions(&self) -> Result<Vec<(String, Subscriber)>, Error>;
+    async fn get_subscribers(&self, actor: &str) -> Result<Vec<Subscriber>, Error>;
+    async fn get_follows_count(&self) -> Result<i64, Error>;
+    async fn get_followers_count(&self) -> Result<i64, Error>;
+    async fn get_follows(&self, actor: Option<&str>, limit: i64, offset: i64) -> Result<Vec<Follow>, Error>;
+    async fn import_follow(&self, follow: &Follow) -> Result<bool, Error>;
+    async fn set_follow_software(&self, id: &str, name: &str, version: Option<&str>) -> Result<(), Error>;
+    /// Returns whether the follow exists
+    async fn set_follow_filter(&self, id: &str, actor: &str, filter: &Filter) -> Result<bool, Error>;
+
+    async fn add_deliveries(&self, inboxes: &[String], actor: &str, post_url: &str, body: &[u8]) -> Result<Vec<i64>, Error>;
+    async fn del_delivery(&self, id: i64) -> Result<(), Error>;
+    async fn retry_delivery(&self, id: i64, attempts: i32) -> Result<(), Error>;
+    async fn get_deliveries(&self, after: i64, limit: i64) -> Result<Vec<Delivery>, Error>;
+    async fn prune_delivery_bodies(&self) -> Result<u64, Error>;
+
+    async fn record_delivery_success(&self, inbox: &str) -> Result<(), Error>;
+    async fn record_delivery_failure(&self, inbox: &str) -> Result<(), Error>;
+    async fn prune_dead_inboxes(&self, failures: i32, failing_for: Duration) -> Result<Vec<(String, String, String)>, Error>;
+    async fn get_inbox_health(&self, limit: i64, offset: i64) -> Result<Vec<InboxHealth>, Error>;
+
+    async fn add_announcement(&self, stream: &str, status_id: &str, announcement: &Announcement) -> Result<(), Error>;
+    async fn get_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error>;
+    async fn take_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error>;
+    async fn prune_announcements(&self, max_age: Duration) -> Result<u64, Error>;
+
+    async fn add_pending_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, follow: &str, filter: &Filter) -> Result<(), Error>;
+    async fn get_pending_follows(&self) -> Result<Vec<PendingFollow>, Error>;
+    async fn get_pending_follow(&self, id: &str, actor: &str) -> Result<Option<PendingFollow>, Error>;
+    async fn del_pending_follow(&self, id: &str, actor: &str) -> Result<(), Error>;
+
+    async fn add_domain_block(&self, domain: &str) -> Result<(), Error>;
+    async fn del_domain_block(&self, domain: &str) -> Result<(), Error>;
+    async fn get_domain_blocks(&self) -> Result<Vec<String>, Error>;
+}
+
+/// How many follows to load at once when scanning all of them
+const FOLLOWS_PAGE: i64 = 1000;
+
+/// Filters are stored as their query string, and only ever written
+/// by us
+fn parse_filter(filter: &str) -> Filter {
+    filter.parse()
+        .unwrap_or_default()
+}
+
+/// Whether `inbox` is on `domain` or one of its subdomains, with
+/// any scheme or port
+fn is_on_domain(inbox: &str, domain: &str) -> bool {
+    let Some(host) = reqwest::Url::parse(inbox).ok()
+        .and_then(|url| url.host_str().map(str::to_lowercase))
+    else {
+        return false;
+    };
+    let domain = domain.to_lowercase();
+    host == domain || host.strip_suffix(&domain).is_some_and(|sub| sub.ends_with('.'))
+}
+
+/// Selects the storage backend by the `db` config key:
+/// `memory`, `sqlite:<path>`, or a PostgreSQL connection string
+fn open(db: &str) -> Result<Arc<dyn Storage>, Error> {
+    if db == "memory" {
+        Ok(Arc::new(memory::Memory::default()))
+    } else if let Some(path) = db.strip_prefix("sqlite:") {
+        Ok(Arc::new(sqlite::Sqlite::open(path)?))
+    } else {
+        Ok(Arc::new(postgres::Postgres::new(db)))
+    }
+}
+
+#[derive(Clone)]
+pub struct Database {
+    storage: Arc<dyn Storage>,
+    subscriptions: Subscriptions,
+    /// Whether `subscriptions` is loaded and kept up to date
+    indexed: bool,
+}
+
+impl Database {
+    /// Brings the schema up to date without preparing for anything else
+    pub async fn migrate(db: &str) -> usize {
+        open(db)
+            .expect("open database")
+            .migrate()
+            .await
+            .expect("migrate database")
+    }
+
+    /// For one-off commands that only query and change follows,
+    /// without loading the subscription index or watching for
+    /// changes
+    pub async fn open(db: &str) -> Self {
+        let storage = open(db)
+            .expect("open database");
+        storage.migrate()
+            .await
+            .expect("migrate database");
+
+        Database {
+            storage,
+            subscriptions: Subscriptions::default(),
+            indexed: false,
+        }
+    }
+
+    pub async fn connect(db: &str) -> Self {
+        let database = Database {
+            indexed: true,
+            ..Database::open(db).await
+        };
+        database.load_subscriptions()
+            .await
+            .expect("load subscriptions");
+        database.spawn_watch();
+        database
+    }
+
+    /// Reads all follows into the subscription index
+    async fn load_subscriptions(&self) -> Result<(), Error> {
+        let subscriptions = self.storage.get_subscriptions().await?;
+        tracing::info!("loaded {} subscriptions", subscriptions.len());
+        self.subscriptions.replace_all(subscriptions);
+        Ok(())
+    }
+
+    /// Refreshes the subscriptions of an actor after its follows changed
+    async fn reload_subscriptions(&self, actor: &str) {
+        if ! self.indexed {
+            return;
+        }
+        match self.storage.get_subscribers(actor).await {
+            Ok(subscribers) =>
+                self.subscriptions.set(actor, subscribers),
+            Err(e) =>
+                tracing::error!("get_subscribers: {}", e),
+        }
+    }
+
+    async fn reload_subscriptions_of(&self, actors: impl Iterator<Item = &str>) {
+        for actor in actors.collect::<HashSet<_>>() {
+            self.reload_subscriptions(actor).await;
+        }
+    }
+
+    /// Keeps the subscriptions up to date with changes by other
+    /// instances, or through the database directly
+    fn spawn_watch(&self) {
+        let Some(mut changes) = self.storage.watch_follows() else {
+            return;
+        };
+        let database = self.clone();
+        tokio::spawn(async move {
+            while let Some(change) = changes.next().await {
+                match change {
+                    FollowsChanged::All => {
+                        if let Err(e) = database.load_subscriptions().await {
+                            tracing::error!("load_subscriptions: {}", e);
+                        }
+                    }
+                    FollowsChanged::Actor(actor) =>
+                        database.reload_subscriptions(&actor).await,
+                }
+            }
+        });
+    }
+
+    /// Replaces any follow by the same `id` through another inbox,
+    /// updates the filter of an existing one
+    pub async fn add_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, filter: &Filter) -> Result<(), Error> {
+        self.storage.add_follow(id, inbox, actor, shared_inbox, filter).await?;
+        self.reload_subscriptions(actor).await;
+        Ok(())
+    }
+
+    pub async fn del_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        self.storage.del_follow(id, actor).await?;
+        self.reload_subscriptions(actor).await;
+        Ok(())
+    }
+
+    /// From the subscription index, without a query
+    pub fn get_subscribers(&self, actor: &str) -> Arc<Vec<Subscriber>> {
+        self.subscriptions.get(actor)
+    }
+
+    /// The matcher over followed keywords, for a post's content
+    pub fn get_keywords(&self) -> Arc<Keywords> {
+        self.subscriptions.get_keywords()
+    }
+
+    /// Followed composite actors that involve `tag`, from the
+    /// subscription index
+    pub fn get_composite_actors(&self, tag: &str) -> Vec<Actor> {
+        self.subscriptions.get_composites(tag)
+    }
+
+    pub async fn get_follows_count(&self) -> Result<i64, Error> {
+        self.storage.get_follows_count().await
+    }
+
+    pub async fn get_followers_count(&self) -> Result<i64, Error> {
+        self.storage.get_followers_count().await
+    }
+
+    /// A page of follows, of one actor or of all
+    pub async fn get_follows(&self, actor: Option<&str>, limit: i64, offset: i64) -> Result<Vec<Follow>, Error> {
+        self.storage.get_follows(actor, limit, offset).await
+    }
+
+    /// Add a follow unless its follower already follows the actor.
+    ///
+    /// Returns whether it was added.
+    pub async fn import_follow(&self, follow: &Follow) -> Result<bool, Error> {
+        let added = self.storage.import_follow(follow).await?;
+        if added {
+            self.reload_subscriptions(&follow.actor).await;
+        }
+        Ok(added)
+    }
+
+    /// Remember what software a follower runs
+    pub async fn set_follow_software(&self, id: &str, name: &str, version: Option<&str>) -> Result<(), Error> {
+        self.storage.set_follow_software(id, name, version).await
+    }
+
+    /// Change what a follower gets delivered.
+    ///
+    /// Returns whether the follow exists.
+    pub async fn set_follow_filter(&self, id: &str, actor: &str, filter: &Filter) -> Result<bool, Error> {
+        let found = self.storage.set_follow_filter(id, actor, filter).await?;
+        if found {
+            self.reload_subscriptions(actor).await;
+        }
+        Ok(found)
+    }
+
+    /// Delete all follows with inboxes on `domain` or its subdomains
+    pub async fn del_domain_follows(&self, domain: &str) -> Result<Vec<Follow>, Error> {
+        let mut removed = vec![];
+        let mut offset = 0;
+        loop {
+            let follows = self.storage.get_follows(None, FOLLOWS_PAGE, offset).await?;
+            let len = follows.len() as i64;
+            removed.extend(follows.into_iter()
+                .filter(|follow| is_on_domain(&follow.inbox, domain)));
+            if len < FOLLOWS_PAGE {
+                break;
+            }
+            offset += len;
+        }
+        for follow in &removed {
+            self.storage.del_follow(&follow.id, &follow.actor).await?;
+        }
+        self.reload_subscriptions_of(removed.iter().map(|follow| follow.actor.as_str())).await;
+        Ok(removed)
+    }
+
+    /// Persist an outbound activity for all of its inboxes before
+    /// handing it to the workers, storing `body` once.
+    ///
+    /// Returns the ids in the order of `inboxes`.
+    pub async fn add_deliveries(&self, inboxes: &[String], actor: &str, post_url: &str, body: &[u8]) -> Result<Vec<i64>, Error> {
+        self.storage.add_deliveries(inboxes, actor, post_url, body).await
+    }
+
+    /// Acknowledge a delivery once a worker is done with it
+    pub async fn del_delivery(&self, id: i64) -> Result<(), Error> {
+        self.storage.del_delivery(id).await
+    }
+
+    /// Remember how often a delivery has failed so that a restart
+    /// does not reset its retry budget
+    pub async fn retry_delivery(&self, id: i64, attempts: i32) -> Result<(), Error> {
+        self.storage.retry_delivery(id, attempts).await
+    }
+
+    /// A page of unacknowledged deliveries with ids after `after`,
+    /// oldest first
+    pub async fn get_deliveries(&self, after: i64, limit: i64) -> Result<Vec<Delivery>, Error> {
+        self.storage.get_deliveries(after, limit).await
+    }
+
+    /// Delete bodies that were left over by concurrently acknowledged
+    /// deliveries
+    pub async fn prune_delivery_bodies(&self) -> Result<u64, Error> {
+        self.storage.prune_delivery_bodies().await
+    }
+
+    /// Reset the failure count of an inbox and note the delivery on
+    /// its follows
+    pub async fn record_delivery_success(&self, inbox: &str) -> Result<(), Error> {
+        self.storage.record_delivery_success(inbox).await
+    }
+
+    /// Count a delivery that was given up on
+    pub async fn record_delivery_failure(&self, inbox: &str) -> Result<(), Error> {
+        self.storage.record_delivery_failure(inbox).await
+    }
+
+    /// Delete all follows of inboxes that have failed at least
+    /// `failures` times in a row for longer than `failing_for`.
+    ///
+    /// Returns the removed `(id, inbox, actor)` rows.
+    pub async fn prune_dead_inboxes(&self, failures: i32, failing_for: Duration) -> Result<Vec<(String, String, String)>, Error> {
+        let pruned = self.storage.prune_dead_inboxes(failures, failing_for).await?;
+        self.reload_subscriptions_of(pruned.iter().map(|(_, _, actor)| actor.as_str())).await;
+        Ok(pruned)
+    }
+
+    /// A page of inbox health, most failing first
+    pub async fn get_inbox_health(&self, limit: i64, offset: i64) -> Result<Vec<InboxHealth>, Error> {
+        self.storage.get_inbox_health(limit, offset).await
+    }
+
+    /// Remember which inboxes received a post from `stream` so that
+    /// it can be retracted later
+    pub async fn add_announcement(&self, stream: &str, status_id: &str, announcement: &Announcement) -> Result<(), Error> {
+        self.storage.add_announcement(stream, status_id, announcement).await
+    }
+
+    /// The announcements of a status
+    pub async fn get_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        self.storage.get_announcements(stream, status_id).await
+    }
+
+    /// Remove and return the announcements of a status
+    pub async fn take_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        self.storage.take_announcements(stream, status_id).await
+    }
+
+    /// Forget announcements that are older than `max_age`
+    pub async fn prune_announcements(&self, max_age: Duration) -> Result<u64, Error> {
+        self.storage.prune_announcements(max_age).await
+    }
+
+    /// Store a `Follow` until an admin decides on it
+    pub async fn add_pending_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, follow: &str, filter: &Filter) -> Result<(), Error> {
+        self.storage.add_pending_follow(id, inbox, actor, shared_inbox, follow, filter).await
+    }
+
+    /// All pending follows, oldest first
+    pub async fn get_pending_follows(&self) -> Result<Vec<PendingFollow>, Error> {
+        self.storage.get_pending_follows().await
+    }
+
+    pub async fn get_pending_follow(&self, id: &str, actor: &str) -> Result<Option<PendingFollow>, Error> {
+        self.storage.get_pending_follow(id, actor).await
+    }
+
+    pub async fn del_pending_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        self.storage.del_pending_follow(id, actor).await
+    }
+
+    pub async fn add_domain_block(&self, domain: &str) -> Result<(), Error> {
+        self.storage.add_domain_block(domain).await
+    }
+
+    pub async fn del_domain_block(&self, domain: &str) -> Result<(), Error> {
+        self.storage.del_domain_block(domain).await
+    }
+
+    /// Domains blocked through the admin API
+    pub async fn get_domain_blocks(&self) -> Result<Vec<String>, Error> {
+        self.storage.get_domain_blocks().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn follows(db: &str) {
+        let database = Database::connect(db).await;
+        database.add_follow("https://a.example/u", "https://a.example/inbox", "relay/tag/rust", true, &Filter::default()).await.unwrap();
+        database.add_follow("https://b.example/u", "https://b.example/inbox", "relay/tag/rust", false, &Filter::default()).await.unwrap();
+        database.add_follow("https://a.example/u", "https://a.example/inbox", "relay/instance/x", true, &Filter::default()).await.unwrap();
+        assert_eq!(database.get_follows_count().await.unwrap(), 3);
+        assert_eq!(database.get_followers_count().await.unwrap(), 2);
+        let mut inboxes = database.get_subscribers("relay/tag/rust").iter()
+            .map(|subscriber| subscriber.inbox.clone())
+            .collect::<Vec<_>>();
+        inboxes.sort();
+        assert_eq!(inboxes, ["https://a.example/inbox", "https://b.example/inbox"]);
+
+        // moved to another inbox
+        database.add_follow("https://b.example/u", "https://b.example/shared", "relay/tag/rust", true, &Filter::default()).await.unwrap();
+        let follows = database.get_follows(Some("relay/tag/rust"), 10, 0).await.unwrap();
+        assert_eq!(follows.len(), 2);
+        assert_eq!(follows[1].inbox, "https://b.example/shared");
+        assert!(follows[1].created_at.is_some());
+
+        database.set_follow_software("https://b.example/u", "mastodon", Some("4.3.0")).await.unwrap();
+        database.record_delivery_success("https://b.example/shared").await.unwrap();
+        let follows = database.get_follows(None, 1, 2).await.unwrap();
+        assert_eq!(follows[0].software_name.as_deref(), Some("mastodon"));
+        assert!(follows[0].last_delivered_at.is_some());
+
+        assert!(! database.import_follow(&follows[0]).await.unwrap());
+        database.del_follow("https://b.example/u", "relay/tag/rust").await.unwrap();
+        assert_eq!(database.get_subscribers("relay/tag/rust")[0].inbox, "https://a.example/inbox");
+        assert!(database.import_follow(&follows[0]).await.unwrap());
+        assert_eq!(database.get_subscribers("relay/tag/rust").len(), 2);
+
+        // filters are updated by following again, or by an admin
+        let filter: Filter = "languages=en".parse().unwrap();
+        database.add_follow("https://a.example/u", "https://a.example/inbox", "relay/tag/rust", true, &filter).await.unwrap();
+        assert_eq!(database.get_follows_count().await.unwrap(), 3);
+        assert!(database.get_subscribers("relay/tag/rust").contains(&Subscriber {
+            inbox: "https://a.example/inbox".to_string(),
+            filter: filter.clone(),
+        }));
+        assert!(database.set_follow_filter("https://a.example/u", "relay/instance/x", &filter).await.unwrap());
+        assert!(! database.set_follow_filter("https://c.example/u", "relay/instance/x", &filter).await.unwrap());
+        assert_eq!(database.get_follows(Some("relay/instance/x"), 1, 0).await.unwrap()[0].filter, filter);
+        assert_eq!(database.get_subscribers("relay/instance/x")[0].filter, filter);
+
+        // followers sharing an inbox keep their own filters
+        let other: Filter = "media_only=true".parse().unwrap();
+        database.add_follow("https://a.example/v", "https://a.example/inbox", "relay/tag/rust", true, &other).await.unwrap();
+        assert_eq!(database.get_follows_count().await.unwrap(), 4);
+        let subscribers = database.get_subscribers("relay/tag/rust");
+        assert!(subscribers.contains(&Subscriber {
+            inbox: "https://a.example/inbox".to_string(),
+            filter: filter.clone(),
+        }));
+        assert!(subscribers.contains(&Subscriber {
+            inbox: "https://a.example/inbox".to_string(),
+            filter: other,
+        }));
+
+        database.add_follow("https://c.example/u", "http://c.a.example:8080/inbox", "relay/tag/rust", false, &Filter::default()).await.unwrap();
+        database.add_follow("https://d.example/u", "https://da.example/inbox", "relay/tag/rust", false, &Filter::default()).await.unwrap();
+        let removed = database.del_domain_follows("a.example").await.unwrap();
+        assert_eq!(removed.len(), 4);
+        assert_eq!(database.get_subscribers("relay/instance/x").len(), 0);
+        assert_eq!(database.get_follows_count().await.unwrap(), 2);
+    }
+
+    #[test]
+    fn on_domain() {
+        assert!(is_on_domain("https://a.example/inbox", "a.example"));
+        assert!(is_on_domain("https://A.Example:8443/inbox", "a.example"));
+        assert!(is_on_domain("http://social.a.example/inbox", "a.example"));
+        assert!(! is_on_domain("https://xa.example/inbox", "a.example"));
+        assert!(! is_on_domain("https://axb.example/inbox", "a_b.example"));
+        assert!(! is_on_domain("https://b.example/a.example/inbox", "a.example"));
+        assert!(! is_on_domain("not a url", "a.example"));
+    }
+
+    async fn deliveries(db: &str) {
+        let database = Database::connect(db).await;
+        let inboxes = ["https://a.example/inbox".to_string(), "https://b.example/inbox".to_string()];
+        let ids = database.add_deliveries(&inboxes, "relay/tag/rust", "https://c.example/p/1", b"{}").await.unwrap();
+        let [id1, id2] = ids[..] else { panic!("{:?}", ids) };
+        assert!(id1 < id2);
+        database.retry_delivery(id2, 3).await.unwrap();
+        database.del_delivery(id1).await.unwrap();
+        let deliveries = database.get_deliveries(0, 100).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].id, id2);
+        assert_eq!(deliveries[0].attempts, 3);
+        assert_eq!(deliveries[0].body, b"{}");
+        assert_eq!(deliveries[0].inbox, "https://b.example/inbox");
+        assert!(database.get_deliveries(id2, 100).await.unwrap().is_empty());
+        database.del_delivery(id2).await.unwrap();
+        assert_eq!(database.prune_delivery_bodies().await.unwrap(), 0);
+
+        database.add_follow("https://b.example/u", "https://b.example/inbox", "relay/tag/rust", false, &Filter::default()).await.unwrap();
+        database.record_delivery_failure("https://b.example/inbox").await.unwrap();
+        database.record_delivery_failure("https://b.example/inbox").await.unwrap();
+        let health = database.get_inbox_health(10, 0).await.unwrap();
+        assert_eq!(health[0].failures, 2);
+        assert!(health[0].failing_since.is_some());
+        assert!(database.prune_dead_inboxes(3, Duration::ZERO).await.unwrap().is_empty());
+        assert!(database.prune_dead_inboxes(2, Duration::from_secs(3600)).await.unwrap().is_empty());
+    }
+
+    async fn announcements(db: &str) {
+        let database = Database::connect(db).await;
+        let announcement = Announcement {
+            post_url: "https://c.example/p/1".to_string(),
+            uri: "https://relay.example/announce/1".to_string(),
+            actor: "relay/tag/rust".to_string(),
+            inboxes: vec!["https://a.example/inbox".to_string()],
+        };
+        database.add_announcement("c.example", "1", &announcement).await.unwrap();
+        assert_eq!(database.get_announcements("c.example", "1").await.unwrap().len(), 1);
+        assert_eq!(database.prune_announcements(Duration::from_secs(3600)).await.unwrap(), 0);
+        let taken = database.take_announcements("c.example", "1").await.unwrap();
+        assert_eq!(taken[0].inboxes, announcement.inboxes);
+        assert!(database.get_announcements("c.example", "1").await.unwrap().is_empty());
+    }
+
+    async fn moderation(db: &str) {
+        let database = Database::connect(db).await;
+        let filter: Filter = "media_only=true".parse().unwrap();
+        database.add_pending_follow("https://a.example/u", "https://a.example/inbox", "relay/tag/rust", false, "{}", &Filter::default()).await.unwrap();
+        database.add_pending_follow("https://a.example/u", "https://a.example/shared", "relay/tag/rust", true, "{}", &filter).await.unwrap();
+        let pending = database.get_pending_follows().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].inbox, "https://a.example/shared");
+        assert_eq!(pending[0].filter, filter);
+        assert!(database.get_pending_follow("https://a.example/u", "relay/tag/rust").await.unwrap().is_some());
+        database.del_pending_follow("https://a.example/u", "relay/tag/rust").await.unwrap();
+        assert!(database.get_pending_follow("https://a.example/u", "relay/tag/rust").await.unwrap().is_none());
+
+        database.add_domain_block("b.example").await.unwrap();
+        database.add_domain_block("a.example").await.unwrap();
+        database.add_domain_block("b.example").await.unwrap();
+        assert_eq!(database.get_domain_blocks().await.unwrap(), ["a.example", "b.example"]);
+        database.del_domain_block("a.example").await.unwrap();
+        assert_eq!(database.get_domain_blocks().await.unwrap(), ["b.example"]);
+    }
+
+    #[tokio::test]
+    async fn unindexed() {
+        let database = Database::open("memory").await;
+        database.add_follow("https://a.example/u", "https://a.example/inbox", "relay/tag/rust", true, &Filter::default()).await.unwrap();
+        assert_eq!(database.get_follows(None, 10, 0).await.unwrap().len(), 1);
+        assert!(database.get_subscribers("relay/tag/rust").is_empty());
+    }
+
+    #[tokio::test]
+    async fn sqlite_watch() {
+        let path = std::env::temp_dir().join(format!("buzzrelay-watch-{}.db", std::process::id()));
+        let db = format!("sqlite:{}", path.display());
+        let server = Database::connect(&db).await;
+        let cli = Database::open(&db).await;
+        cli.add_follow("https://a.example/u", "https://a.example/inbox", "relay/tag/rust", true, &Filter::default()).await.unwrap();
+        let mut found = false;
+        for _ in 0..50 {
+            if ! server.get_subscribers("relay/tag/rust").is_empty() {
+                found = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        assert!(found);
+    }
+
+    #[tokio::test]
+    async fn memory() {
+        follows("memory").await;
+        deliveries("memory").await;
+        announcements("memory").await;
+        moderation("memory").await;
+    }
+
+    #[tokio::test]
+    async fn sqlite() {
+        follows("sqlite::memory:").await;
+        deliveries("sqlite::memory:").await;
+        announcements("sqlite::memory:").await;
+        moderation("sqlite::memory:").await;
+    }
+}
@@ -0,0 +1,682 @@
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod, Runtime};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    future::poll_fn,
+    StreamExt,
+};
+use metrics::histogram;
+use tokio::time::sleep;
+use tokio_postgres::{AsyncMessage, Client, NoTls, Statement};
+use crate::{filter::Filter, subscriptions::Subscriber};
+use super::{parse_filter, Announcement, Delivery, Error, Follow, FollowsChanged, InboxHealth, PendingFollow, Storage};
+
+/// Connections kept open to the database
+const POOL_SIZE: usize = 16;
+/// How long to wait for a connection before failing a query
+const POOL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `NOTIFY` channel for changes to `follows`, with the actor as payload
+const FOLLOWS_CHANNEL: &str = "follows";
+/// Delay before listening again after losing the connection
+const LISTEN_RETRY: Duration = Duration::from_secs(10);
+
+/// Serializes migrations of concurrently starting instances
+const MIGRATIONS_LOCK: i64 = 0x62757a7a72656c61;
+
+/// Ordered schema migrations, each applied in one transaction.
+/// Version `n` is `MIGRATIONS[n - 1]`. Never change a migration that
+/// has been released, append a new one instead.
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: the schema before it was versioned. Idempotent so that
+    // existing databases get upgraded in place.
+    &[
+        "CREATE TABLE IF NOT EXISTS follows (id TEXT NOT NULL, inbox TEXT NOT NULL, actor TEXT NOT NULL, UNIQUE (inbox, actor))",
+        "CREATE INDEX IF NOT EXISTS follows_actor ON follows (actor) INCLUDE (inbox)",
+        "CREATE TABLE IF NOT EXISTS deliveries (id BIGSERIAL PRIMARY KEY, inbox TEXT NOT NULL, actor TEXT NOT NULL, post_url TEXT NOT NULL, body BYTEA NOT NULL, created_at TIMESTAMPTZ NOT NULL DEFAULT now())",
+        "ALTER TABLE deliveries ADD COLUMN IF NOT EXISTS attempts INT NOT NULL DEFAULT 0",
+        "CREATE TABLE IF NOT EXISTS inbox_health (inbox TEXT PRIMARY KEY, failures INT NOT NULL DEFAULT 0, failing_since TIMESTAMPTZ, last_success TIMESTAMPTZ)",
+        "CREATE TABLE IF NOT EXISTS announcements (stream TEXT NOT NULL, status_id TEXT NOT NULL, post_url TEXT NOT NULL, uri TEXT NOT NULL, actor TEXT NOT NULL, inboxes TEXT[] NOT NULL, created_at TIMESTAMPTZ NOT NULL DEFAULT now())",
+        "CREATE INDEX IF NOT EXISTS announcements_status ON announcements (stream, status_id)",
+        "CREATE TABLE IF NOT EXISTS pending_follows (id TEXT NOT NULL, inbox TEXT NOT NULL, actor TEXT NOT NULL, follow TEXT NOT NULL, created_at TIMESTAMPTZ NOT NULL DEFAULT now(), UNIQUE (id, actor))",
+        "CREATE TABLE IF NOT EXISTS domain_blocks (domain TEXT PRIMARY KEY, created_at TIMESTAMPTZ NOT NULL DEFAULT now())",
+    ],
+    // 2: follow metadata, unknown for existing follows
+    &[
+        "ALTER TABLE follows ADD COLUMN created_at TIMESTAMPTZ",
+        "ALTER TABLE follows ALTER COLUMN created_at SET DEFAULT now()",
+        "ALTER TABLE follows ADD COLUMN last_delivered_at TIMESTAMPTZ",
+        "ALTER TABLE follows ADD COLUMN software_name TEXT",
+        "ALTER TABLE follows ADD COLUMN software_version TEXT",
+        "ALTER TABLE follows ADD COLUMN shared_inbox BOOLEAN NOT NULL DEFAULT FALSE",
+        "ALTER TABLE pending_follows ADD COLUMN shared_inbox BOOLEAN NOT NULL DEFAULT FALSE",
+    ],
+    // 3: let all instances update their subscriptions on follow changes
+    &[
+        "CREATE FUNCTION follows_notify() RETURNS trigger AS $$
+        BEGIN
+            IF TG_OP <> 'INSERT' THEN
+                PERFORM pg_notify('follows', OLD.actor);
+            END IF;
+            IF TG_OP <> 'DELETE' THEN
+                PERFORM pg_notify('follows', NEW.actor);
+            END IF;
+            RETURN NULL;
+        END
+        $$ LANGUAGE plpgsql",
+        "CREATE TRIGGER follows_notify AFTER INSERT OR DELETE OR UPDATE OF inbox, actor ON follows FOR EACH ROW EXECUTE FUNCTION follows_notify()",
+    ],
+    // 4: per-follower delivery filters
+    &[
+        "ALTER TABLE follows ADD COLUMN filter TEXT NOT NULL DEFAULT ''",
+        "ALTER TABLE pending_follows ADD COLUMN filter TEXT NOT NULL DEFAULT ''",
+        "DROP TRIGGER follows_notify ON follows",
+        "CREATE TRIGGER follows_notify AFTER INSERT OR DELETE OR UPDATE OF inbox, actor, filter ON follows FOR EACH ROW EXECUTE FUNCTION follows_notify()",
+    ],
+    // 5: one body for all deliveries of an activity
+    &[
+        "CREATE TABLE delivery_bodies (id BIGSERIAL PRIMARY KEY, body BYTEA NOT NULL)",
+        "ALTER TABLE deliveries ADD COLUMN body_id BIGINT REFERENCES delivery_bodies (id)",
+        "INSERT INTO delivery_bodies (id, body) SELECT id, body FROM deliveries",
+        "UPDATE deliveries SET body_id=id",
+        "SELECT setval(pg_get_serial_sequence('delivery_bodies', 'id'), (SELECT COALESCE(MAX(id), 0) + 1 FROM delivery_bodies), false)",
+        "ALTER TABLE deliveries DROP COLUMN body",
+        "ALTER TABLE deliveries ALTER COLUMN body_id SET NOT NULL",
+        "CREATE INDEX deliveries_body ON deliveries (body_id)",
+    ],
+    // 6: one follow per follower and actor, so that followers sharing
+    // an inbox keep their own filters
+    &[
+        "DELETE FROM follows a USING follows b WHERE a.id=b.id AND a.actor=b.actor AND a.ctid<b.ctid",
+        "ALTER TABLE follows DROP CONSTRAINT IF EXISTS follows_inbox_actor_key",
+        "ALTER TABLE follows ADD CONSTRAINT follows_id_actor_key UNIQUE (id, actor)",
+    ],
+];
+
+const ADD_FOLLOW: &str = "INSERT INTO follows (id, inbox, actor, shared_inbox, filter) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id, actor) DO UPDATE SET inbox=$2, shared_inbox=$4, filter=$5";
+const DEL_FOLLOW: &str = "DELETE FROM follows WHERE id=$1 AND actor=$2";
+const GET_SUBSCRIPTIONS: &str = "SELECT actor, inbox, filter FROM follows";
+const GET_SUBSCRIBERS: &str = "SELECT inbox, filter FROM follows WHERE actor=$1";
+const GET_FOLLOWS_COUNT: &str = "SELECT COUNT(id) FROM follows";
+const GET_FOLLOWERS_COUNT: &str = "SELECT COUNT(DISTINCT id) FROM follows";
+const ADD_DELIVERIES: &str = "WITH body AS (INSERT INTO delivery_bodies (body) VALUES ($4) RETURNING id) INSERT INTO deliveries (inbox, actor, post_url, body_id) SELECT inboxes.inbox, $2, $3, body.id FROM unnest($1::TEXT[]) WITH ORDINALITY AS inboxes (inbox, n), body ORDER BY inboxes.n RETURNING id";
+const DEL_DELIVERY: &str = "WITH deleted AS (DELETE FROM deliveries WHERE id=$1 RETURNING body_id) DELETE FROM delivery_bodies WHERE id IN (SELECT body_id FROM deleted) AND NOT EXISTS (SELECT 1 FROM deliveries WHERE body_id=delivery_bodies.id AND id<>$1)";
+const GET_DELIVERIES: &str = "SELECT deliveries.id, inbox, actor, post_url, body, attempts FROM deliveries JOIN delivery_bodies ON delivery_bodies.id=body_id WHERE deliveries.id>$1 ORDER BY deliveries.id LIMIT $2";
+const PRUNE_DELIVERY_BODIES: &str = "DELETE FROM delivery_bodies WHERE NOT EXISTS (SELECT 1 FROM deliveries WHERE body_id=delivery_bodies.id)";
+const RETRY_DELIVERY: &str = "UPDATE deliveries SET attempts=$2 WHERE id=$1";
+const RECORD_DELIVERY_SUCCESS: &str = "WITH health AS (INSERT INTO inbox_health (inbox, failures, failing_since, last_success) VALUES ($1, 0, NULL, now()) ON CONFLICT (inbox) DO UPDATE SET failures=0, failing_since=NULL, last_success=now()) UPDATE follows SET last_delivered_at=now() WHERE inbox=$1";
+const RECORD_DELIVERY_FAILURE: &str = "INSERT INTO inbox_health (inbox, failures, failing_since) VALUES ($1, 1, now()) ON CONFLICT (inbox) DO UPDATE SET failures=inbox_health.failures+1, failing_since=COALESCE(inbox_health.failing_since, now())";
+const PRUNE_DEAD_INBOXES: &str = "WITH dead AS (DELETE FROM inbox_health WHERE failures>=$1 AND failing_since<now()-make_interval(secs => $2) RETURNING inbox) DELETE FROM follows WHERE inbox IN (SELECT inbox FROM dead) RETURNING id, inbox, actor";
+const ADD_ANNOUNCEMENT: &str = "INSERT INTO announcements (stream, status_id, post_url, uri, actor, inboxes) VALUES ($1, $2, $3, $4, $5, $6)";
+const GET_ANNOUNCEMENTS: &str = "SELECT post_url, uri, actor, inboxes FROM announcements WHERE stream=$1 AND status_id=$2";
+const TAKE_ANNOUNCEMENTS: &str = "DELETE FROM announcements WHERE stream=$1 AND status_id=$2 RETURNING post_url, uri, actor, inboxes";
+const PRUNE_ANNOUNCEMENTS: &str = "DELETE FROM announcements WHERE created_at<now()-make_interval(secs => $1)";
+const ADD_PENDING_FOLLOW: &str = "INSERT INTO pending_follows (id, inbox, actor, follow, shared_inbox, filter) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (id, actor) DO UPDATE SET inbox=$2, follow=$4, shared_inbox=$5, filter=$6";
+const GET_PENDING_FOLLOWS: &str = "SELECT id, inbox, actor, follow, EXTRACT(EPOCH FROM created_at)::BIGINT, shared_inbox, filter FROM pending_follows ORDER BY created_at";
+const GET_PENDING_FOLLOW: &str = "SELECT id, inbox, actor, follow, EXTRACT(EPOCH FROM created_at)::BIGINT, shared_inbox, filter FROM pending_follows WHERE id=$1 AND actor=$2";
+const DEL_PENDING_FOLLOW: &str = "DELETE FROM pending_follows WHERE id=$1 AND actor=$2";
+const GET_FOLLOWS: &str = "SELECT id, inbox, actor, EXTRACT(EPOCH FROM created_at)::BIGINT, EXTRACT(EPOCH FROM last_delivered_at)::BIGINT, software_name, software_version, shared_inbox, filter FROM follows ORDER BY actor, id LIMIT $1 OFFSET $2";
+const IMPORT_FOLLOW: &str = "INSERT INTO follows (id, inbox, actor, created_at, last_delivered_at, software_name, software_version, shared_inbox, filter) VALUES ($1, $2, $3, COALESCE(to_timestamp($4), now()), to_timestamp($5), $6, $7, $8, $9) ON CONFLICT (id, actor) DO NOTHING";
+const GET_ACTOR_FOLLOWS: &str = "SELECT id, inbox, actor, EXTRACT(EPOCH FROM created_at)::BIGINT, EXTRACT(EPOCH FROM last_delivered_at)::BIGINT, software_name, software_version, shared_inbox, filter FROM follows WHERE actor=$1 ORDER BY id LIMIT $2 OFFSET $3";
+const ADD_DOMAIN_BLOCK: &str = "INSERT INTO domain_blocks (domain) VALUES ($1) ON CONFLICT (domain) DO NOTHING";
+const DEL_DOMAIN_BLOCK: &str = "DELETE FROM domain_blocks WHERE domain=$1";
+const GET_DOMAIN_BLOCKS: &str = "SELECT domain FROM domain_blocks ORDER BY domain";
+const SET_FOLLOW_FILTER: &str = "UPDATE follows SET filter=$3 WHERE id=$1 AND actor=$2";
+const SET_FOLLOW_SOFTWARE: &str = "UPDATE follows SET software_name=$2, software_version=$3 WHERE id=$1";
+const GET_INBOX_HEALTH: &str = "SELECT inbox, failures, EXTRACT(EPOCH FROM failing_since)::BIGINT, EXTRACT(EPOCH FROM last_success)::BIGINT FROM inbox_health ORDER BY failures DESC, inbox LIMIT $1 OFFSET $2";
+
+/// PostgreSQL, with a pool of connections
+pub struct Postgres {
+    conn_str: String,
+    pool: Pool,
+}
+
+/// Applies all pending migrations, returning how many
+async fn migrate(client: &mut Client) -> Result<usize, tokio_postgres::Error> {
+    client.execute("SELECT pg_advisory_lock($1)", &[&MIGRATIONS_LOCK])
+        .await?;
+    let result = apply_migrations(client).await;
+    client.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATIONS_LOCK])
+        .await?;
+    result
+}
+
+async fn apply_migrations(client: &mut Client) -> Result<usize, tokio_postgres::Error> {
+    client.execute("CREATE TABLE IF NOT EXISTS schema_migrations (version INT PRIMARY KEY, applied_at TIMESTAMPTZ NOT NULL DEFAULT now())", &[])
+        .await?;
+
+    let mut applied = 0;
+    for (index, commands) in MIGRATIONS.iter().enumerate() {
+        let version = index as i32 + 1;
+        let transaction = client.transaction().await?;
+        let done = transaction.query_opt("SELECT 1 FROM schema_migrations WHERE version=$1", &[&version])
+            .await?
+            .is_some();
+        if done {
+            continue;
+        }
+
+        tracing::info!("migrating database to version {}", version);
+        for command in *commands {
+            transaction.execute(*command, &[])
+                .await?;
+        }
+        transaction.execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[&version])
+            .await?;
+        transaction.commit().await?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+/// Reconnects on demand, so that queries fail while the database is
+/// down but work again once it is back.
+fn create_pool(conn_str: &str) -> Pool {
+    let pg_config: tokio_postgres::Config = conn_str.parse()
+        .expect("db");
+    let manager = Manager::from_config(pg_config, NoTls, ManagerConfig {
+        recycling_method: RecyclingMethod::Fast,
+    });
+    Pool::builder(manager)
+        .max_size(POOL_SIZE)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(POOL_TIMEOUT))
+        .create_timeout(Some(POOL_TIMEOUT))
+        .build()
+        .expect("database pool")
+}
+
+impl Postgres {
+    pub fn new(conn_str: &str) -> Self {
+        Postgres {
+            conn_str: conn_str.to_string(),
+            pool: create_pool(conn_str),
+        }
+    }
+
+    /// A pooled connection with `query` prepared on it
+    async fn prepare(&self, query: &str) -> Result<(Object, Statement), Error> {
+        let client = self.pool.get().await?;
+        let statement = client.prepare_cached(query).await?;
+        Ok((client, statement))
+    }
+}
+
+/// Reports changes until the connection is lost
+async fn listen(conn_str: &str, tx: &UnboundedSender<FollowsChanged>) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(conn_str, NoTls)
+        .await?;
+    let (notifications_tx, mut notifications_rx) = unbounded();
+    tokio::spawn(async move {
+        while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    if notifications_tx.unbounded_send(notification.payload().to_string()).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!("postgresql: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+    client.batch_execute(&format!("LISTEN {FOLLOWS_CHANNEL}"))
+        .await?;
+    // catch up with what was missed while not listening
+    if tx.unbounded_send(FollowsChanged::All).is_err() {
+        return Ok(());
+    }
+
+    while let Some(actor) = notifications_rx.next().await {
+        tracing::debug!("follows of {} changed", actor);
+        if tx.unbounded_send(FollowsChanged::Actor(actor)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for Postgres {
+    async fn migrate(&self) -> Result<usize, Error> {
+        let mut client = self.pool.get().await?;
+        Ok(migrate(&mut client).await?)
+    }
+
+    /// Notified by all instances, and changes through the database
+    /// directly
+    fn watch_follows(&self) -> Option<UnboundedReceiver<FollowsChanged>> {
+        let (tx, rx) = unbounded();
+        let conn_str = self.conn_str.clone();
+        tokio::spawn(async move {
+            while ! tx.is_closed() {
+                if let Err(e) = listen(&conn_str, &tx).await {
+                    tracing::error!("listen {}: {}", FOLLOWS_CHANNEL, e);
+                }
+                sleep(LISTEN_RETRY).await;
+            }
+        });
+        Some(rx)
+    }
+
+    async fn get_subscriptions(&self) -> Result<Vec<(String, Subscriber)>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(GET_SUBSCRIPTIONS).await?;
+        let rows = client.query(&statement, &[])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_subscriptions")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(|row| (row.get(0), Subscriber {
+               inbox: row.get(1),
+               filter: parse_filter(row.get(2)),
+           }))
+           .collect()
+        )
+    }
+
+    async fn get_subscribers(&self, actor: &str) -> Result<Vec<Subscriber>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(GET_SUBSCRIBERS).await?;
+        let rows = client.query(&statement, &[&actor])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_subscribers")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(|row| Subscriber {
+               inbox: row.get(0),
+               filter: parse_filter(row.get(1)),
+           })
+           .collect()
+        )
+    }
+
+    async fn add_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, filter: &Filter) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(ADD_FOLLOW).await?;
+        client.execute(&statement, &[&id, &inbox, &actor, &shared_inbox, &filter.to_string()])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "add_follow")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn del_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(DEL_FOLLOW).await?;
+        client.execute(&statement, &[&id, &actor])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "del_follow")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn get_follows_count(&self) -> Result<i64, Error> {
+        let (client, statement) = self.prepare(GET_FOLLOWS_COUNT).await?;
+        let row = client.query_one(&statement, &[])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn get_followers_count(&self) -> Result<i64, Error> {
+        let (client, statement) = self.prepare(GET_FOLLOWERS_COUNT).await?;
+        let row = client.query_one(&statement, &[])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn add_deliveries(&self, inboxes: &[String], actor: &str, post_url: &str, body: &[u8]) -> Result<Vec<i64>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(ADD_DELIVERIES).await?;
+        let rows = client.query(&statement, &[&inboxes, &actor, &post_url, &body])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "add_deliveries")
+            .record(t2 - t1);
+        // ids are taken in the order of `inboxes`
+        let mut ids = rows.into_iter()
+            .map(|row| row.get(0))
+            .collect::<Vec<i64>>();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    async fn del_delivery(&self, id: i64) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(DEL_DELIVERY).await?;
+        client.execute(&statement, &[&id])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "del_delivery")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn retry_delivery(&self, id: i64, attempts: i32) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(RETRY_DELIVERY).await?;
+        client.execute(&statement, &[&id, &attempts])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "retry_delivery")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn get_deliveries(&self, after: i64, limit: i64) -> Result<Vec<Delivery>, Error> {
+        let (client, statement) = self.prepare(GET_DELIVERIES).await?;
+        let rows = client.query(&statement, &[&after, &limit])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| Delivery {
+               id: row.get(0),
+               inbox: row.get(1),
+               actor: row.get(2),
+               post_url: row.get(3),
+               body: row.get(4),
+               attempts: row.get(5),
+           })
+           .collect()
+        )
+    }
+
+    async fn prune_delivery_bodies(&self) -> Result<u64, Error> {
+        let (client, statement) = self.prepare(PRUNE_DELIVERY_BODIES).await?;
+        let count = client.execute(&statement, &[])
+            .await?;
+        Ok(count)
+    }
+
+    async fn record_delivery_success(&self, inbox: &str) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(RECORD_DELIVERY_SUCCESS).await?;
+        client.execute(&statement, &[&inbox])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "record_delivery_success")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn record_delivery_failure(&self, inbox: &str) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(RECORD_DELIVERY_FAILURE).await?;
+        client.execute(&statement, &[&inbox])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "record_delivery_failure")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn prune_dead_inboxes(&self, failures: i32, failing_for: Duration) -> Result<Vec<(String, String, String)>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(PRUNE_DEAD_INBOXES).await?;
+        let rows = client.query(&statement, &[&failures, &failing_for.as_secs_f64()])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "prune_dead_inboxes")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(|row| (row.get(0), row.get(1), row.get(2)))
+           .collect()
+        )
+    }
+
+    async fn add_announcement(&self, stream: &str, status_id: &str, announcement: &Announcement) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(ADD_ANNOUNCEMENT).await?;
+        client.execute(&statement, &[
+            &stream, &status_id,
+            &announcement.post_url, &announcement.uri,
+            &announcement.actor, &announcement.inboxes,
+        ]).await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "add_announcement")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn get_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(GET_ANNOUNCEMENTS).await?;
+        let rows = client.query(&statement, &[&stream, &status_id])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_announcements")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(announcement_from_row)
+           .collect()
+        )
+    }
+
+    async fn take_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(TAKE_ANNOUNCEMENTS).await?;
+        let rows = client.query(&statement, &[&stream, &status_id])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "take_announcements")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(announcement_from_row)
+           .collect()
+        )
+    }
+
+    async fn prune_announcements(&self, max_age: Duration) -> Result<u64, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(PRUNE_ANNOUNCEMENTS).await?;
+        let count = client.execute(&statement, &[&max_age.as_secs_f64()])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "prune_announcements")
+            .record(t2 - t1);
+        Ok(count)
+    }
+
+    async fn add_pending_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, follow: &str, filter: &Filter) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(ADD_PENDING_FOLLOW).await?;
+        client.execute(&statement, &[&id, &inbox, &actor, &follow, &shared_inbox, &filter.to_string()])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "add_pending_follow")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn get_pending_follows(&self) -> Result<Vec<PendingFollow>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(GET_PENDING_FOLLOWS).await?;
+        let rows = client.query(&statement, &[])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_pending_follows")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(pending_follow_from_row)
+           .collect()
+        )
+    }
+
+    async fn get_pending_follow(&self, id: &str, actor: &str) -> Result<Option<PendingFollow>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(GET_PENDING_FOLLOW).await?;
+        let row = client.query_opt(&statement, &[&id, &actor])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_pending_follow")
+            .record(t2 - t1);
+        Ok(row.map(pending_follow_from_row))
+    }
+
+    async fn del_pending_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(DEL_PENDING_FOLLOW).await?;
+        client.execute(&statement, &[&id, &actor])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "del_pending_follow")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn get_follows(&self, actor: Option<&str>, limit: i64, offset: i64) -> Result<Vec<Follow>, Error> {
+        let t1 = Instant::now();
+        let rows = match actor {
+            Some(actor) => {
+                let (client, statement) = self.prepare(GET_ACTOR_FOLLOWS).await?;
+                client.query(&statement, &[&actor, &limit, &offset])
+                    .await?
+            }
+            None => {
+                let (client, statement) = self.prepare(GET_FOLLOWS).await?;
+                client.query(&statement, &[&limit, &offset])
+                    .await?
+            }
+        };
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_follows")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(follow_from_row)
+           .collect()
+        )
+    }
+
+    async fn import_follow(&self, follow: &Follow) -> Result<bool, Error> {
+        let t1 = Instant::now();
+        let created_at = follow.created_at.map(|t| t as f64);
+        let last_delivered_at = follow.last_delivered_at.map(|t| t as f64);
+        let (client, statement) = self.prepare(IMPORT_FOLLOW).await?;
+        let count = client.execute(&statement, &[
+            &follow.id, &follow.inbox, &follow.actor,
+            &created_at, &last_delivered_at,
+            &follow.software_name, &follow.software_version,
+            &follow.shared_inbox, &follow.filter.to_string(),
+        ])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "import_follow")
+            .record(t2 - t1);
+        Ok(count > 0)
+    }
+
+    async fn set_follow_software(&self, id: &str, name: &str, version: Option<&str>) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(SET_FOLLOW_SOFTWARE).await?;
+        client.execute(&statement, &[&id, &name, &version])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "set_follow_software")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn set_follow_filter(&self, id: &str, actor: &str, filter: &Filter) -> Result<bool, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(SET_FOLLOW_FILTER).await?;
+        let count = client.execute(&statement, &[&id, &actor, &filter.to_string()])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "set_follow_filter")
+            .record(t2 - t1);
+        Ok(count > 0)
+    }
+
+    async fn add_domain_block(&self, domain: &str) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(ADD_DOMAIN_BLOCK).await?;
+        client.execute(&statement, &[&domain])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "add_domain_block")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn del_domain_block(&self, domain: &str) -> Result<(), Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(DEL_DOMAIN_BLOCK).await?;
+        client.execute(&statement, &[&domain])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "del_domain_block")
+            .record(t2 - t1);
+        Ok(())
+    }
+
+    async fn get_domain_blocks(&self) -> Result<Vec<String>, Error> {
+        let (client, statement) = self.prepare(GET_DOMAIN_BLOCKS).await?;
+        let rows = client.query(&statement, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| row.get(0))
+           .collect()
+        )
+    }
+
+    async fn get_inbox_health(&self, limit: i64, offset: i64) -> Result<Vec<InboxHealth>, Error> {
+        let t1 = Instant::now();
+        let (client, statement) = self.prepare(GET_INBOX_HEALTH).await?;
+        let rows = client.query(&statement, &[&limit, &offset])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", "query" => "get_inbox_health")
+            .record(t2 - t1);
+        Ok(rows.into_iter()
+           .map(|row| InboxHealth {
+               inbox: row.get(0),
+               failures: row.get(1),
+               failing_since: row.get(2),
+               last_success: row.get(3),
+           })
+           .collect()
+        )
+    }
+}
+
+fn follow_from_row(row: tokio_postgres::Row) -> Follow {
+    Follow {
+        id: row.get(0),
+        inbox: row.get(1),
+        actor: row.get(2),
+        created_at: row.get(3),
+        last_delivered_at: row.get(4),
+        software_name: row.get(5),
+        software_version: row.get(6),
+        shared_inbox: row.get(7),
+        filter: parse_filter(row.get(8)),
+    }
+}
+
+fn pending_follow_from_row(row: tokio_postgres::Row) -> PendingFollow {
+    PendingFollow {
+        id: row.get(0),
+        inbox: row.get(1),
+        actor: row.get(2),
+        follow: row.get(3),
+        created_at: row.get(4),
+        shared_inbox: row.get(5),
+        filter: parse_filter(row.get(6)),
+    }
+}
+
+fn announcement_from_row(row: tokio_postgres::Row) -> Announcement {
+    Announcement {
+        post_url: row.get(0),
+        uri: row.get(1),
+        actor: row.get(2),
+        inboxes: row.get(3),
+    }
+}
@@ -0,0 +1,528 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use tokio::time::sleep;
+use crate::{filter::Filter, subscriptions::Subscriber};
+use super::{parse_filter, Announcement, Delivery, Error, Follow, FollowsChanged, InboxHealth, PendingFollow, Storage};
+
+/// How long to wait for a lock held by another process
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to look for changes by other processes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Ordered schema migrations, each applied in one transaction.
+/// Version `n` is `MIGRATIONS[n - 1]`, tracked in `user_version`.
+/// Never change a migration that has been released, append a new one
+/// instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: everything up to follow metadata
+    "CREATE TABLE follows (id TEXT NOT NULL, inbox TEXT NOT NULL, actor TEXT NOT NULL, created_at INTEGER DEFAULT (unixepoch()), last_delivered_at INTEGER, software_name TEXT, software_version TEXT, shared_inbox INTEGER NOT NULL DEFAULT 0, UNIQUE (inbox, actor));
+     CREATE INDEX follows_actor ON follows (actor, inbox);
+     CREATE TABLE deliveries (id INTEGER PRIMARY KEY AUTOINCREMENT, inbox TEXT NOT NULL, actor TEXT NOT NULL, post_url TEXT NOT NULL, body BLOB NOT NULL, attempts INTEGER NOT NULL DEFAULT 0, created_at INTEGER NOT NULL DEFAULT (unixepoch()));
+     CREATE TABLE inbox_health (inbox TEXT PRIMARY KEY, failures INTEGER NOT NULL DEFAULT 0, failing_since INTEGER, last_success INTEGER);
+     CREATE TABLE announcements (stream TEXT NOT NULL, status_id TEXT NOT NULL, post_url TEXT NOT NULL, uri TEXT NOT NULL, actor TEXT NOT NULL, inboxes TEXT NOT NULL, created_at INTEGER NOT NULL DEFAULT (unixepoch()));
+     CREATE INDEX announcements_status ON announcements (stream, status_id);
+     CREATE TABLE pending_follows (id TEXT NOT NULL, inbox TEXT NOT NULL, actor TEXT NOT NULL, follow TEXT NOT NULL, shared_inbox INTEGER NOT NULL DEFAULT 0, created_at INTEGER NOT NULL DEFAULT (unixepoch()), UNIQUE (id, actor));
+     CREATE TABLE domain_blocks (domain TEXT PRIMARY KEY, created_at INTEGER NOT NULL DEFAULT (unixepoch()));",
+    // 2: per-follower delivery filters
+    "ALTER TABLE follows ADD COLUMN filter TEXT NOT NULL DEFAULT '';
+     ALTER TABLE pending_follows ADD COLUMN filter TEXT NOT NULL DEFAULT '';",
+    // 3: one body for all deliveries of an activity
+    "CREATE TABLE delivery_bodies (id INTEGER PRIMARY KEY AUTOINCREMENT, body BLOB NOT NULL);
+     ALTER TABLE deliveries ADD COLUMN body_id INTEGER REFERENCES delivery_bodies (id);
+     INSERT INTO delivery_bodies (id, body) SELECT id, body FROM deliveries;
+     UPDATE deliveries SET body_id=id;
+     ALTER TABLE deliveries DROP COLUMN body;
+     CREATE INDEX deliveries_body ON deliveries (body_id);",
+    // 4: one follow per follower and actor, so that followers sharing
+    // an inbox keep their own filters
+    "CREATE TABLE follows_new (id TEXT NOT NULL, inbox TEXT NOT NULL, actor TEXT NOT NULL, created_at INTEGER DEFAULT (unixepoch()), last_delivered_at INTEGER, software_name TEXT, software_version TEXT, shared_inbox INTEGER NOT NULL DEFAULT 0, filter TEXT NOT NULL DEFAULT '', UNIQUE (id, actor));
+     INSERT INTO follows_new (id, inbox, actor, created_at, last_delivered_at, software_name, software_version, shared_inbox, filter) SELECT id, inbox, actor, created_at, last_delivered_at, software_name, software_version, shared_inbox, filter FROM follows WHERE rowid IN (SELECT MAX(rowid) FROM follows GROUP BY id, actor);
+     DROP TABLE follows;
+     ALTER TABLE follows_new RENAME TO follows;
+     CREATE INDEX follows_actor ON follows (actor, inbox);",
+];
+
+const ADD_FOLLOW: &str = "INSERT INTO follows (id, inbox, actor, shared_inbox, filter) VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT (id, actor) DO UPDATE SET inbox=?2, shared_inbox=?4, filter=?5";
+const DEL_FOLLOW: &str = "DELETE FROM follows WHERE id=?1 AND actor=?2";
+const GET_SUBSCRIPTIONS: &str = "SELECT actor, inbox, filter FROM follows";
+const GET_SUBSCRIBERS: &str = "SELECT inbox, filter FROM follows WHERE actor=?1";
+const GET_FOLLOWS_COUNT: &str = "SELECT COUNT(id) FROM follows";
+const GET_FOLLOWERS_COUNT: &str = "SELECT COUNT(DISTINCT id) FROM follows";
+const ADD_DELIVERY_BODY: &str = "INSERT INTO delivery_bodies (body) VALUES (?1) RETURNING id";
+const ADD_DELIVERY: &str = "INSERT INTO deliveries (inbox, actor, post_url, body_id) VALUES (?1, ?2, ?3, ?4) RETURNING id";
+const DEL_DELIVERY: &str = "DELETE FROM deliveries WHERE id=?1 RETURNING body_id";
+const DEL_DELIVERY_BODY: &str = "DELETE FROM delivery_bodies WHERE id=?1 AND NOT EXISTS (SELECT 1 FROM deliveries WHERE body_id=?1)";
+const GET_DELIVERIES: &str = "SELECT deliveries.id, inbox, actor, post_url, body, attempts FROM deliveries JOIN delivery_bodies ON delivery_bodies.id=body_id WHERE deliveries.id>?1 ORDER BY deliveries.id LIMIT ?2";
+const PRUNE_DELIVERY_BODIES: &str = "DELETE FROM delivery_bodies WHERE NOT EXISTS (SELECT 1 FROM deliveries WHERE body_id=delivery_bodies.id)";
+const RETRY_DELIVERY: &str = "UPDATE deliveries SET attempts=?2 WHERE id=?1";
+const RECORD_INBOX_SUCCESS: &str = "INSERT INTO inbox_health (inbox, failures, failing_since, last_success) VALUES (?1, 0, NULL, unixepoch()) ON CONFLICT (inbox) DO UPDATE SET failures=0, failing_since=NULL, last_success=unixepoch()";
+const RECORD_FOLLOWS_DELIVERED: &str = "UPDATE follows SET last_delivered_at=unixepoch() WHERE inbox=?1";
+const RECORD_DELIVERY_FAILURE: &str = "INSERT INTO inbox_health (inbox, failures, failing_since) VALUES (?1, 1, unixepoch()) ON CONFLICT (inbox) DO UPDATE SET failures=failures+1, failing_since=COALESCE(failing_since, unixepoch())";
+const PRUNE_DEAD_INBOXES: &str = "DELETE FROM inbox_health WHERE failures>=?1 AND failing_since<unixepoch()-?2 RETURNING inbox";
+const DEL_INBOX_FOLLOWS: &str = "DELETE FROM follows WHERE inbox=?1 RETURNING id, inbox, actor";
+const ADD_ANNOUNCEMENT: &str = "INSERT INTO announcements (stream, status_id, post_url, uri, actor, inboxes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+const GET_ANNOUNCEMENTS: &str = "SELECT post_url, uri, actor, inboxes FROM announcements WHERE stream=?1 AND status_id=?2";
+const TAKE_ANNOUNCEMENTS: &str = "DELETE FROM announcements WHERE stream=?1 AND status_id=?2 RETURNING post_url, uri, actor, inboxes";
+const PRUNE_ANNOUNCEMENTS: &str = "DELETE FROM announcements WHERE created_at<unixepoch()-?1";
+const ADD_PENDING_FOLLOW: &str = "INSERT INTO pending_follows (id, inbox, actor, follow, shared_inbox, filter) VALUES (?1, ?2, ?3, ?4, ?5, ?6) ON CONFLICT (id, actor) DO UPDATE SET inbox=?2, follow=?4, shared_inbox=?5, filter=?6";
+const GET_PENDING_FOLLOWS: &str = "SELECT id, inbox, actor, follow, created_at, shared_inbox, filter FROM pending_follows ORDER BY created_at, rowid";
+const GET_PENDING_FOLLOW: &str = "SELECT id, inbox, actor, follow, created_at, shared_inbox, filter FROM pending_follows WHERE id=?1 AND actor=?2";
+const DEL_PENDING_FOLLOW: &str = "DELETE FROM pending_follows WHERE id=?1 AND actor=?2";
+const GET_FOLLOWS: &str = "SELECT id, inbox, actor, created_at, last_delivered_at, software_name, software_version, shared_inbox, filter FROM follows ORDER BY actor, id LIMIT ?1 OFFSET ?2";
+const IMPORT_FOLLOW: &str = "INSERT INTO follows (id, inbox, actor, created_at, last_delivered_at, software_name, software_version, shared_inbox, filter) VALUES (?1, ?2, ?3, COALESCE(?4, unixepoch()), ?5, ?6, ?7, ?8, ?9) ON CONFLICT (id, actor) DO NOTHING";
+const GET_ACTOR_FOLLOWS: &str = "SELECT id, inbox, actor, created_at, last_delivered_at, software_name, software_version, shared_inbox, filter FROM follows WHERE actor=?1 ORDER BY id LIMIT ?2 OFFSET ?3";
+const ADD_DOMAIN_BLOCK: &str = "INSERT INTO domain_blocks (domain) VALUES (?1) ON CONFLICT (domain) DO NOTHING";
+const DEL_DOMAIN_BLOCK: &str = "DELETE FROM domain_blocks WHERE domain=?1";
+const GET_DOMAIN_BLOCKS: &str = "SELECT domain FROM domain_blocks ORDER BY domain";
+const SET_FOLLOW_FILTER: &str = "UPDATE follows SET filter=?3 WHERE id=?1 AND actor=?2";
+const SET_FOLLOW_SOFTWARE: &str = "UPDATE follows SET software_name=?2, software_version=?3 WHERE id=?1";
+const GET_INBOX_HEALTH: &str = "SELECT inbox, failures, failing_since, last_success FROM inbox_health ORDER BY failures DESC, inbox LIMIT ?1 OFFSET ?2";
+
+/// Only changes when another connection commits
+fn data_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("PRAGMA data_version", [], |row| row.get(0))
+}
+
+/// A single SQLite file, for small deployments on one server
+pub struct Sqlite {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    /// `path` may be `:memory:` for a database that lives as long as
+    /// the process
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        Ok(Sqlite {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the connection without blocking the runtime
+    async fn call<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        }).await?;
+        Ok(result?)
+    }
+}
+
+#[async_trait]
+impl Storage for Sqlite {
+    async fn migrate(&self) -> Result<usize, Error> {
+        self.call(|conn| {
+            let transaction = conn.transaction()?;
+            let version: usize = transaction.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+            for (index, commands) in MIGRATIONS.iter().enumerate().skip(version) {
+                tracing::info!("migrating database to version {}", index + 1);
+                transaction.execute_batch(commands)?;
+                transaction.pragma_update(None, "user_version", index + 1)?;
+            }
+            transaction.commit()?;
+            Ok(MIGRATIONS.len().saturating_sub(version))
+        }).await
+    }
+
+    /// Changes by other processes, like the `follows` command, as
+    /// SQLite cannot notify about them
+    fn watch_follows(&self) -> Option<UnboundedReceiver<FollowsChanged>> {
+        let conn = self.conn.lock().unwrap();
+        if conn.path().is_none_or(str::is_empty) {
+            return None;
+        }
+        // from now on, not when the task gets to it
+        let mut last_version = data_version(&conn)
+            .map_err(|e| tracing::error!("data_version: {}", e))
+            .ok();
+        drop(conn);
+        let (tx, rx) = unbounded();
+        let sqlite = Sqlite {
+            conn: self.conn.clone(),
+        };
+        tokio::spawn(async move {
+            while ! tx.is_closed() {
+                sleep(WATCH_INTERVAL).await;
+                match sqlite.call(|conn| data_version(conn)).await {
+                    Ok(version) => {
+                        if last_version.is_some_and(|last| last != version) {
+                            tracing::debug!("database changed by another process");
+                            if tx.unbounded_send(FollowsChanged::All).is_err() {
+                                break;
+                            }
+                        }
+                        last_version = Some(version);
+                    }
+                    Err(e) =>
+                        tracing::error!("data_version: {}", e),
+                }
+            }
+        });
+        Some(rx)
+    }
+
+    async fn add_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, filter: &Filter) -> Result<(), Error> {
+        let (id, inbox, actor, filter) = (id.to_string(), inbox.to_string(), actor.to_string(), filter.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(ADD_FOLLOW)?
+                .execute(params![id, inbox, actor, shared_inbox, filter])?;
+            Ok(())
+        }).await
+    }
+
+    async fn del_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        let (id, actor) = (id.to_string(), actor.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(DEL_FOLLOW)?
+                .execute(params![id, actor])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_subscriptions(&self) -> Result<Vec<(String, Subscriber)>, Error> {
+        self.call(|conn| {
+            conn.prepare_cached(GET_SUBSCRIPTIONS)?
+                .query_map([], |row| Ok((row.get(0)?, Subscriber {
+                    inbox: row.get(1)?,
+                    filter: parse_filter(row.get_ref(2)?.as_str()?),
+                })))?
+                .collect()
+        }).await
+    }
+
+    async fn get_subscribers(&self, actor: &str) -> Result<Vec<Subscriber>, Error> {
+        let actor = actor.to_string();
+        self.call(move |conn| {
+            conn.prepare_cached(GET_SUBSCRIBERS)?
+                .query_map([actor], |row| Ok(Subscriber {
+                    inbox: row.get(0)?,
+                    filter: parse_filter(row.get_ref(1)?.as_str()?),
+                }))?
+                .collect()
+        }).await
+    }
+
+    async fn get_follows_count(&self) -> Result<i64, Error> {
+        self.call(|conn| conn.query_row(GET_FOLLOWS_COUNT, [], |row| row.get(0))).await
+    }
+
+    async fn get_followers_count(&self) -> Result<i64, Error> {
+        self.call(|conn| conn.query_row(GET_FOLLOWERS_COUNT, [], |row| row.get(0))).await
+    }
+
+    async fn get_follows(&self, actor: Option<&str>, limit: i64, offset: i64) -> Result<Vec<Follow>, Error> {
+        let actor = actor.map(str::to_string);
+        self.call(move |conn| {
+            match actor {
+                Some(actor) =>
+                    conn.prepare_cached(GET_ACTOR_FOLLOWS)?
+                        .query_map(params![actor, limit, offset], follow_from_row)?
+                        .collect(),
+                None =>
+                    conn.prepare_cached(GET_FOLLOWS)?
+                        .query_map(params![limit, offset], follow_from_row)?
+                        .collect(),
+            }
+        }).await
+    }
+
+    async fn import_follow(&self, follow: &Follow) -> Result<bool, Error> {
+        let follow = follow.clone();
+        self.call(move |conn| {
+            let count = conn.prepare_cached(IMPORT_FOLLOW)?
+                .execute(params![
+                    follow.id, follow.inbox, follow.actor,
+                    follow.created_at, follow.last_delivered_at,
+                    follow.software_name, follow.software_version,
+                    follow.shared_inbox, follow.filter.to_string(),
+                ])?;
+            Ok(count > 0)
+        }).await
+    }
+
+    async fn set_follow_software(&self, id: &str, name: &str, version: Option<&str>) -> Result<(), Error> {
+        let (id, name, version) = (id.to_string(), name.to_string(), version.map(str::to_string));
+        self.call(move |conn| {
+            conn.prepare_cached(SET_FOLLOW_SOFTWARE)?
+                .execute(params![id, name, version])?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_follow_filter(&self, id: &str, actor: &str, filter: &Filter) -> Result<bool, Error> {
+        let (id, actor, filter) = (id.to_string(), actor.to_string(), filter.to_string());
+        self.call(move |conn| {
+            let count = conn.prepare_cached(SET_FOLLOW_FILTER)?
+                .execute([id, actor, filter])?;
+            Ok(count > 0)
+        }).await
+    }
+
+    async fn add_deliveries(&self, inboxes: &[String], actor: &str, post_url: &str, body: &[u8]) -> Result<Vec<i64>, Error> {
+        let (inboxes, actor, post_url, body) = (inboxes.to_vec(), actor.to_string(), post_url.to_string(), body.to_vec());
+        self.call(move |conn| {
+            let transaction = conn.transaction()?;
+            let body_id: i64 = transaction.prepare_cached(ADD_DELIVERY_BODY)?
+                .query_row([body], |row| row.get(0))?;
+            let ids = {
+                let mut statement = transaction.prepare_cached(ADD_DELIVERY)?;
+                inboxes.iter()
+                    .map(|inbox| statement.query_row(params![inbox, actor, post_url, body_id], |row| row.get(0)))
+                    .collect::<rusqlite::Result<Vec<i64>>>()?
+            };
+            transaction.commit()?;
+            Ok(ids)
+        }).await
+    }
+
+    async fn del_delivery(&self, id: i64) -> Result<(), Error> {
+        self.call(move |conn| {
+            let transaction = conn.transaction()?;
+            let body_id: Option<i64> = transaction.prepare_cached(DEL_DELIVERY)?
+                .query_row([id], |row| row.get(0))
+                .optional()?;
+            if let Some(body_id) = body_id {
+                transaction.prepare_cached(DEL_DELIVERY_BODY)?
+                    .execute([body_id])?;
+            }
+            transaction.commit()
+        }).await
+    }
+
+    async fn retry_delivery(&self, id: i64, attempts: i32) -> Result<(), Error> {
+        self.call(move |conn| {
+            conn.prepare_cached(RETRY_DELIVERY)?
+                .execute(params![id, attempts])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_deliveries(&self, after: i64, limit: i64) -> Result<Vec<Delivery>, Error> {
+        self.call(move |conn| {
+            conn.prepare_cached(GET_DELIVERIES)?
+                .query_map([after, limit], |row| Ok(Delivery {
+                    id: row.get(0)?,
+                    inbox: row.get(1)?,
+                    actor: row.get(2)?,
+                    post_url: row.get(3)?,
+                    body: row.get(4)?,
+                    attempts: row.get(5)?,
+                }))?
+                .collect()
+        }).await
+    }
+
+    async fn prune_delivery_bodies(&self) -> Result<u64, Error> {
+        self.call(|conn| {
+            let count = conn.prepare_cached(PRUNE_DELIVERY_BODIES)?
+                .execute([])?;
+            Ok(count as u64)
+        }).await
+    }
+
+    async fn record_delivery_success(&self, inbox: &str) -> Result<(), Error> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            let transaction = conn.transaction()?;
+            transaction.prepare_cached(RECORD_INBOX_SUCCESS)?
+                .execute([&inbox])?;
+            transaction.prepare_cached(RECORD_FOLLOWS_DELIVERED)?
+                .execute([&inbox])?;
+            transaction.commit()
+        }).await
+    }
+
+    async fn record_delivery_failure(&self, inbox: &str) -> Result<(), Error> {
+        let inbox = inbox.to_string();
+        self.call(move |conn| {
+            conn.prepare_cached(RECORD_DELIVERY_FAILURE)?
+                .execute([inbox])?;
+            Ok(())
+        }).await
+    }
+
+    async fn prune_dead_inboxes(&self, failures: i32, failing_for: Duration) -> Result<Vec<(String, String, String)>, Error> {
+        let failing_for = failing_for.as_secs() as i64;
+        self.call(move |conn| {
+            let transaction = conn.transaction()?;
+            let dead = transaction.prepare_cached(PRUNE_DEAD_INBOXES)?
+                .query_map(params![failures, failing_for], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut pruned = vec![];
+            for inbox in dead {
+                let mut statement = transaction.prepare_cached(DEL_INBOX_FOLLOWS)?;
+                let rows = statement.query_map([inbox], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+                for row in rows {
+                    pruned.push(row?);
+                }
+            }
+            transaction.commit()?;
+            Ok(pruned)
+        }).await
+    }
+
+    async fn get_inbox_health(&self, limit: i64, offset: i64) -> Result<Vec<InboxHealth>, Error> {
+        self.call(move |conn| {
+            conn.prepare_cached(GET_INBOX_HEALTH)?
+                .query_map(params![limit, offset], |row| Ok(InboxHealth {
+                    inbox: row.get(0)?,
+                    failures: row.get(1)?,
+                    failing_since: row.get(2)?,
+                    last_success: row.get(3)?,
+                }))?
+                .collect()
+        }).await
+    }
+
+    async fn add_announcement(&self, stream: &str, status_id: &str, announcement: &Announcement) -> Result<(), Error> {
+        let (stream, status_id, announcement) = (stream.to_string(), status_id.to_string(), announcement.clone());
+        self.call(move |conn| {
+            let inboxes = serde_json::to_string(&announcement.inboxes)
+                .unwrap();
+            conn.prepare_cached(ADD_ANNOUNCEMENT)?
+                .execute(params![
+                    stream, status_id,
+                    announcement.post_url, announcement.uri,
+                    announcement.actor, inboxes,
+                ])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        let (stream, status_id) = (stream.to_string(), status_id.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(GET_ANNOUNCEMENTS)?
+                .query_map([stream, status_id], announcement_from_row)?
+                .collect()
+        }).await
+    }
+
+    async fn take_announcements(&self, stream: &str, status_id: &str) -> Result<Vec<Announcement>, Error> {
+        let (stream, status_id) = (stream.to_string(), status_id.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(TAKE_ANNOUNCEMENTS)?
+                .query_map([stream, status_id], announcement_from_row)?
+                .collect()
+        }).await
+    }
+
+    async fn prune_announcements(&self, max_age: Duration) -> Result<u64, Error> {
+        let max_age = max_age.as_secs() as i64;
+        self.call(move |conn| {
+            let count = conn.prepare_cached(PRUNE_ANNOUNCEMENTS)?
+                .execute([max_age])?;
+            Ok(count as u64)
+        }).await
+    }
+
+    async fn add_pending_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: bool, follow: &str, filter: &Filter) -> Result<(), Error> {
+        let (id, inbox, actor, follow, filter) = (id.to_string(), inbox.to_string(), actor.to_string(), follow.to_string(), filter.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(ADD_PENDING_FOLLOW)?
+                .execute(params![id, inbox, actor, follow, shared_inbox, filter])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_pending_follows(&self) -> Result<Vec<PendingFollow>, Error> {
+        self.call(|conn| {
+            conn.prepare_cached(GET_PENDING_FOLLOWS)?
+                .query_map([], pending_follow_from_row)?
+                .collect()
+        }).await
+    }
+
+    async fn get_pending_follow(&self, id: &str, actor: &str) -> Result<Option<PendingFollow>, Error> {
+        let (id, actor) = (id.to_string(), actor.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(GET_PENDING_FOLLOW)?
+                .query_row([id, actor], pending_follow_from_row)
+                .optional()
+        }).await
+    }
+
+    async fn del_pending_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+        let (id, actor) = (id.to_string(), actor.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(DEL_PENDING_FOLLOW)?
+                .execute([id, actor])?;
+            Ok(())
+        }).await
+    }
+
+    async fn add_domain_block(&self, domain: &str) -> Result<(), Error> {
+        let domain = domain.to_string();
+        self.call(move |conn| {
+            conn.prepare_cached(ADD_DOMAIN_BLOCK)?
+                .execute([domain])?;
+            Ok(())
+        }).await
+    }
+
+    async fn del_domain_block(&self, domain: &str) -> Result<(), Error> {
+        let domain = domain.to_string();
+        self.call(move |conn| {
+            conn.prepare_cached(DEL_DOMAIN_BLOCK)?
+                .execute([domain])?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_domain_blocks(&self) -> Result<Vec<String>, Error> {
+        self.call(|conn| {
+            conn.prepare_cached(GET_DOMAIN_BLOCKS)?
+                .query_map([], |row| row.get(0))?
+                .collect()
+        }).await
+    }
+}
+
+fn follow_from_row(row: &Row) -> rusqlite::Result<Follow> {
+    Ok(Follow {
+        id: row.get(0)?,
+        inbox: row.get(1)?,
+        actor: row.get(2)?,
+        created_at: row.get(3)?,
+        last_delivered_at: row.get(4)?,
+        software_name: row.get(5)?,
+        software_version: row.get(6)?,
+        shared_inbox: row.get(7)?,
+        filter: parse_filter(row.get_ref(8)?.as_str()?),
+    })
+}
+
+fn pending_follow_from_row(row: &Row) -> rusqlite::Result<PendingFollow> {
+    Ok(PendingFollow {
+        id: row.get(0)?,
+        inbox: row.get(1)?,
+        actor: row.get(2)?,
+        follow: row.get(3)?,
+        created_at: row.get(4)?,
+        shared_inbox: row.get(5)?,
+        filter: parse_filter(row.get_ref(6)?.as_str()?),
+    })
+}
+
+fn announcement_from_row(row: &Row) -> rusqlite::Result<Announcement> {
+    let inboxes: String = row.get(3)?;
+    Ok(Announcement {
+        post_url: row.get(0)?,
+        uri: row.get(1)?,
+        actor: row.get(2)?,
+        inboxes: serde_json::from_str(&inboxes)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, e.into()))?,
+    })
+}
@@ -0,0 +1,66 @@
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+use lru::LruCache;
+
+/// Bounded, time-windowed set of recently relayed post URIs
+///
+/// With multiple streams configured, the same post usually arrives
+/// more than once. As relay targets are derived from the post alone,
+/// skipping a known URI means relaying it once per target inbox.
+pub struct RecentPosts {
+    cache: LruCache<String, Instant>,
+    window: Duration,
+}
+
+impl RecentPosts {
+    pub fn new(capacity: NonZeroUsize, window: Duration) -> Self {
+        RecentPosts {
+            cache: LruCache::new(capacity),
+            window,
+        }
+    }
+
+    /// Remembers `uri`, returning `false` if it was already seen
+    /// within the window.
+    pub fn insert(&mut self, uri: &str) -> bool {
+        if let Some(seen) = self.cache.get(uri) {
+            if seen.elapsed() < self.window {
+                return false;
+            }
+        }
+        self.cache.put(uri.to_string(), Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duplicate() {
+        let mut recent = RecentPosts::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(60));
+        assert!(recent.insert("https://example.com/1"));
+        assert!(!recent.insert("https://example.com/1"));
+        assert!(recent.insert("https://example.com/2"));
+        assert!(!recent.insert("https://example.com/1"));
+    }
+
+    #[test]
+    fn bounded() {
+        let mut recent = RecentPosts::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(60));
+        assert!(recent.insert("https://example.com/1"));
+        assert!(recent.insert("https://example.com/2"));
+        assert!(recent.insert("https://example.com/3"));
+        assert!(recent.insert("https://example.com/1"));
+    }
+
+    #[test]
+    fn expired() {
+        let mut recent = RecentPosts::new(NonZeroUsize::new(2).unwrap(), Duration::ZERO);
+        assert!(recent.insert("https://example.com/1"));
+        assert!(recent.insert("https://example.com/1"));
+    }
+}
@@ -1,4 +1,5 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
+use http::StatusCode;
 
 #[derive(Clone, Debug, thiserror::Error)]
 pub enum Error {
@@ -18,6 +19,12 @@ pub enum Error {
     InvalidUri,
     #[error("Error response from remote")]
     Response(String),
+    #[error("HTTP status {status} from remote")]
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        body: String,
+    },
 }
 
 impl From<serde_json::Error> for Error {
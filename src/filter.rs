@@ -0,0 +1,156 @@
+use std::{fmt, str::FromStr};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use crate::actor::normalize_language;
+
+/// What a follower wants delivered, beyond matching the followed
+/// actor.
+///
+/// Written like the query string that sets it on the inbox URL:
+/// `languages=en,de&media_only=true&no_sensitive=true`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Only posts in one of these languages, any if empty
+    pub languages: Vec<String>,
+    /// Only posts with media attachments
+    pub media_only: bool,
+    /// No posts marked sensitive or behind a content warning
+    pub no_sensitive: bool,
+}
+
+/// The part of a post that filters look at
+pub struct PostAttributes<'a> {
+    pub language: Option<&'a str>,
+    pub has_media: bool,
+    pub sensitive: bool,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        *self == Filter::default()
+    }
+
+    pub fn matches(&self, post: &PostAttributes) -> bool {
+        if self.media_only && ! post.has_media {
+            return false;
+        }
+        if self.no_sensitive && post.sensitive {
+            return false;
+        }
+        if ! self.languages.is_empty() {
+            let Some(language) = post.language.map(normalize_language) else {
+                return false;
+            };
+            if ! self.languages.contains(&language) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter::default();
+        for pair in query.split('&').filter(|pair| ! pair.is_empty()) {
+            let (key, value) = pair.split_once('=')
+                .unwrap_or((pair, ""));
+            let value = urlencoding::decode(value)
+                .map_err(|e| format!("{key}: {e}"))?;
+            let flag = || match value.as_ref() {
+                "" | "true" | "1" => Ok(true),
+                "false" | "0" => Ok(false),
+                _ => Err(format!("{key}: expected true or false")),
+            };
+            match key {
+                "languages" =>
+                    filter.languages = value.split(',')
+                        .map(normalize_language)
+                        .filter(|language| ! language.is_empty())
+                        .collect(),
+                "media_only" =>
+                    filter.media_only = flag()?,
+                "no_sensitive" =>
+                    filter.no_sensitive = flag()?,
+                _ =>
+                    return Err(format!("unknown filter: {key}")),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pairs = vec![];
+        if ! self.languages.is_empty() {
+            pairs.push(format!("languages={}", self.languages.join(",")));
+        }
+        if self.media_only {
+            pairs.push("media_only=true".to_string());
+        }
+        if self.no_sensitive {
+            pairs.push("no_sensitive=true".to_string());
+        }
+        write!(f, "{}", pairs.join("&"))
+    }
+}
+
+impl Serialize for Filter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Filter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let filter: Filter = "languages=en,de_CH&media_only=true&no_sensitive".parse().unwrap();
+        assert_eq!(filter, Filter {
+            languages: vec!["en".to_string(), "de".to_string()],
+            media_only: true,
+            no_sensitive: true,
+        });
+        assert_eq!(filter.to_string(), "languages=en,de&media_only=true&no_sensitive=true");
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+        assert!("".parse::<Filter>().unwrap().is_empty());
+        assert!("media_only=0".parse::<Filter>().unwrap().is_empty());
+        assert!("media_only=maybe".parse::<Filter>().is_err());
+        assert!("langs=en".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let post = PostAttributes {
+            language: Some("en-US"),
+            has_media: false,
+            sensitive: false,
+        };
+        assert!(Filter::default().matches(&post));
+        assert!(Filter { languages: vec!["en".to_string()], ..Filter::default() }.matches(&post));
+        assert!(! Filter { languages: vec!["de".to_string()], ..Filter::default() }.matches(&post));
+        assert!(! Filter { media_only: true, ..Filter::default() }.matches(&post));
+        assert!(Filter { no_sensitive: true, ..Filter::default() }.matches(&post));
+
+        let post = PostAttributes {
+            language: None,
+            has_media: true,
+            sensitive: true,
+        };
+        assert!(Filter { media_only: true, ..Filter::default() }.matches(&post));
+        assert!(! Filter { no_sensitive: true, ..Filter::default() }.matches(&post));
+        assert!(! Filter { languages: vec!["en".to_string()], ..Filter::default() }.matches(&post));
+    }
+}
@@ -0,0 +1,106 @@
+use serde::Deserialize;
+use serde_json::json;
+use crate::{activitypub, actor::Actor, error::Error, send, state::State};
+
+#[derive(Deserialize)]
+struct NodeInfoLinks {
+    links: Vec<NodeInfoLink>,
+}
+
+#[derive(Deserialize)]
+struct NodeInfoLink {
+    rel: String,
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct NodeInfo {
+    software: Software,
+}
+
+/// `software` of a nodeinfo document
+#[derive(Deserialize)]
+pub struct Software {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+/// Whether a follow comes through the follower's `sharedInbox`
+pub fn is_shared_inbox(remote_actor: &activitypub::Actor) -> bool {
+    remote_actor.endpoints.as_ref()
+        .is_some_and(|endpoints| endpoints.shared_inbox == remote_actor.inbox)
+}
+
+/// Looks up the software of an instance through its nodeinfo
+pub async fn fetch_software(client: &reqwest::Client, host: &str) -> Result<Software, Error> {
+    let links: NodeInfoLinks = client.get(format!("https://{host}/.well-known/nodeinfo"))
+        .header("accept", "application/json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    // prefer the latest schema version
+    let link = links.links.into_iter()
+        .filter(|link| link.rel.starts_with("http://nodeinfo.diaspora.software/ns/schema/"))
+        .max_by(|a, b| a.rel.cmp(&b.rel))
+        .ok_or_else(|| Error::Response("no nodeinfo link".to_string()))?;
+    let nodeinfo: NodeInfo = client.get(&link.href)
+        .header("accept", "application/json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(nodeinfo.software)
+}
+
+/// Records the software of a new follower's instance
+pub async fn record_software(state: &State, id: &str, inbox: &str) {
+    let Some(host) = reqwest::Url::parse(inbox).ok()
+        .and_then(|url| url.host_str().map(ToString::to_string))
+    else {
+        return;
+    };
+    match fetch_software(&state.client, &host).await {
+        Ok(software) => {
+            if let Err(e) = state.database.set_follow_software(id, &software.name, software.version.as_deref()).await {
+                tracing::error!("set_follow_software: {}", e);
+            }
+        }
+        Err(e) =>
+            tracing::warn!("nodeinfo of {}: {}", host, e),
+    }
+}
+
+/// Answers a `Follow` with an `Accept` or a `Reject`
+pub async fn send_response(
+    state: &State,
+    target: &Actor,
+    remote_id: &str,
+    remote_inbox: &str,
+    follow: serde_json::Value,
+    action_type: &str,
+) -> Result<(), Error> {
+    let id = format!(
+        "https://{}/activity/{}/{}/{}",
+        state.hostname,
+        action_type.to_lowercase(),
+        urlencoding::encode(&target.uri()),
+        urlencoding::encode(remote_inbox),
+    );
+    let action = activitypub::Action {
+        jsonld_context: serde_json::Value::String("https://www.w3.org/ns/activitystreams".to_string()),
+        action_type: action_type.to_string(),
+        actor: target.uri(),
+        to: Some(json!(remote_id)),
+        id,
+        object: Some(follow),
+    };
+    send::send(
+        state.client.as_ref(), remote_inbox,
+        &target.key_id(),
+        &state.priv_key,
+        &action,
+    ).await
+}
@@ -0,0 +1,169 @@
+use aho_corasick::{AhoCorasick, MatchKind};
+use crate::actor::{Actor, ActorKind};
+
+/// One automaton over all followed keywords, so that each post's
+/// content is scanned once no matter how many there are
+#[derive(Default)]
+pub struct Keywords {
+    automaton: Option<AhoCorasick>,
+    /// By pattern index
+    actors: Vec<Actor>,
+}
+
+/// Plain, lowercase text with single spaces, the form that keywords
+/// are normalized to as well
+pub fn text_of_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find(['<', '&']) {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with('<') {
+            // an unclosed tag runs to the end
+            let end = rest.find('>')
+                .unwrap_or(rest.len());
+            if is_block(&rest[1..end]) {
+                text.push(' ');
+            }
+            rest = rest.get(end + 1..).unwrap_or_default();
+        } else {
+            let entity = rest.find(';')
+                .filter(|end| *end <= 8)
+                .and_then(|end| Some((end, decode_entity(&rest[1..end])?)));
+            match entity {
+                Some((end, c)) => {
+                    text.push(c);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    text.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        }
+    }
+    text.push_str(rest);
+
+    text.to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether a tag separates words, like `<br>` or `</p><p>`, unlike
+/// the `<span>`s within links
+fn is_block(tag: &str) -> bool {
+    let name = tag.trim_start_matches('/')
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    matches!(name.as_str(), "p" | "br" | "div" | "li" | "ul" | "ol" | "blockquote" | "pre")
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+/// Whether `c` continues a word, so that `rust` is not found in
+/// `trust`
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Keywords {
+    pub fn new(actors: impl IntoIterator<Item = Actor>) -> Self {
+        let actors = actors.into_iter()
+            .filter(|actor| matches!(actor.kind, ActorKind::KeywordRelay(_)))
+            .collect::<Vec<_>>();
+        if actors.is_empty() {
+            return Keywords::default();
+        }
+        let patterns = actors.iter()
+            .map(|actor| match &actor.kind {
+                ActorKind::KeywordRelay(keyword) => keyword.as_str(),
+                _ => unreachable!(),
+            });
+        let automaton = AhoCorasick::builder()
+            .match_kind(MatchKind::Standard)
+            .build(patterns)
+            .map_err(|e| tracing::error!("keywords: {}", e))
+            .ok();
+        Keywords { automaton, actors }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actors.is_empty()
+    }
+
+    /// Keyword actors of which the whole words or phrases occur in
+    /// `text`, as returned by [`text_of_html()`]
+    pub fn matches(&self, text: &str) -> Vec<Actor> {
+        let Some(automaton) = &self.automaton else {
+            return vec![];
+        };
+        let mut found = vec![false; self.actors.len()];
+        for m in automaton.find_overlapping_iter(text) {
+            let before = text[..m.start()].chars().next_back();
+            let after = text[m.end()..].chars().next();
+            if ! before.is_some_and(is_word) && ! after.is_some_and(is_word) {
+                found[m.pattern().as_usize()] = true;
+            }
+        }
+        self.actors.iter()
+            .zip(found)
+            .filter(|(_, found)| *found)
+            .map(|(actor, _)| actor.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            text_of_html("<p>Hello <a href=\"x\">World</a>!</p><p>Fish &amp;\nChips&#33;<br/>&lt;3</p>"),
+            "hello world! fish & chips! <3"
+        );
+        assert_eq!(
+            text_of_html("<a href=\"https://example.com/\"><span class=\"invisible\">https://</span><span>example.com</span></a>"),
+            "https://example.com"
+        );
+        assert_eq!(text_of_html("A&B &unknown; C"), "a&b &unknown; c");
+        assert_eq!(text_of_html("a <"), "a");
+    }
+
+    #[test]
+    fn matches() {
+        let actor = |keyword| Actor::from_uri(&format!("https://relay/keyword/{keyword}")).unwrap();
+        let keywords = Keywords::new([
+            actor("rust"),
+            actor("rust%20lang"),
+            actor("open%20source"),
+            actor("Zürich"),
+        ]);
+        let text = text_of_html("<p>I trust <b>Rust</b> Lang in zürich</p>");
+        let found = keywords.matches(&text);
+        assert_eq!(found, vec![actor("rust"), actor("rust%20lang"), actor("zürich")]);
+        assert!(keywords.matches("rustacean").is_empty());
+        assert!(Keywords::default().matches("rust").is_empty());
+    }
+}
@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Path, Query},
+    extract::{Path, Query, RawQuery},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::get, Json, Router,
@@ -13,19 +13,29 @@ use std::{net::SocketAddr, time::Duration, collections::HashMap};
 use std::{panic, process};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use reqwest::Url;
+use clap::Parser;
 
 mod error;
 mod config;
 mod state;
 mod actor;
 mod db;
+mod dedup;
+mod filter;
+mod keywords;
+mod subscriptions;
 mod digest;
 mod fetch;
 mod send;
 mod stream;
 mod relay;
+mod worker;
 mod activitypub;
 mod actor_cache;
+mod blocklist;
+mod follow;
+mod admin;
+mod cli;
 mod endpoint;
 
 use actor::Actor;
@@ -104,33 +114,100 @@ async fn get_language_actor(
         .into_response()
 }
 
+async fn get_account_actor(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(account): Path<String>
+) -> Response {
+    track_request("GET", "actor", "account");
+    let Some(kind) = actor::ActorKind::from_account(&account) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    target.as_activitypub(&state.pub_key)
+        .into_response()
+}
+
+async fn get_tag_language_actor(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((tag, language)): Path<(String, String)>
+) -> Response {
+    track_request("GET", "actor", "tag_language");
+    let Some(kind) = actor::ActorKind::from_tag_language(&tag, &language) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    target.as_activitypub(&state.pub_key)
+        .into_response()
+}
+
+async fn get_tags_actor(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(tags): Path<String>
+) -> Response {
+    track_request("GET", "actor", "tags");
+    let Some(kind) = actor::ActorKind::from_tags(&tags) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    target.as_activitypub(&state.pub_key)
+        .into_response()
+}
+
+async fn get_keyword_actor(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(keyword): Path<String>
+) -> Response {
+    track_request("GET", "actor", "keyword");
+    let Some(kind) = actor::ActorKind::from_keyword(&keyword) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    target.as_activitypub(&state.pub_key)
+        .into_response()
+}
+
 async fn post_tag_relay(
     axum::extract::State(state): axum::extract::State<State>,
     Path(tag): Path<String>,
+    RawQuery(query): RawQuery,
     endpoint: endpoint::Endpoint<'_>
 ) -> Response {
     let target = actor::Actor {
         host: state.hostname.clone(),
         kind: actor::ActorKind::from_tag(&tag),
     };
-    post_relay(state, endpoint, target).await
+    post_relay(state, endpoint, target, query).await
 }
 
 async fn post_instance_relay(
     axum::extract::State(state): axum::extract::State<State>,
     Path(instance): Path<String>,
+    RawQuery(query): RawQuery,
     endpoint: endpoint::Endpoint<'_>
 ) -> Response {
     let target = actor::Actor {
         host: state.hostname.clone(),
         kind: actor::ActorKind::InstanceRelay(instance.to_lowercase()),
     };
-    post_relay(state, endpoint, target).await
+    post_relay(state, endpoint, target, query).await
 }
 
 async fn post_language_relay(
     axum::extract::State(state): axum::extract::State<State>,
     Path(language): Path<String>,
+    RawQuery(query): RawQuery,
     endpoint: endpoint::Endpoint<'_>
 ) -> Response {
     let Some(kind) = actor::ActorKind::from_language(&language) else {
@@ -140,13 +217,79 @@ async fn post_language_relay(
         host: state.hostname.clone(),
         kind,
     };
-    post_relay(state, endpoint, target).await
+    post_relay(state, endpoint, target, query).await
+}
+
+async fn post_account_relay(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(account): Path<String>,
+    RawQuery(query): RawQuery,
+    endpoint: endpoint::Endpoint<'_>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_account(&account) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    post_relay(state, endpoint, target, query).await
+}
+
+async fn post_tag_language_relay(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((tag, language)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    endpoint: endpoint::Endpoint<'_>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_tag_language(&tag, &language) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    post_relay(state, endpoint, target, query).await
+}
+
+async fn post_tags_relay(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(tags): Path<String>,
+    RawQuery(query): RawQuery,
+    endpoint: endpoint::Endpoint<'_>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_tags(&tags) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    post_relay(state, endpoint, target, query).await
+}
+
+async fn post_keyword_relay(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(keyword): Path<String>,
+    RawQuery(query): RawQuery,
+    endpoint: endpoint::Endpoint<'_>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_keyword(&keyword) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    post_relay(state, endpoint, target, query).await
 }
 
+/// `query` of the inbox URL may carry a `filter::Filter` for follows
 async fn post_relay(
     state: State,
     endpoint: endpoint::Endpoint<'_>,
-    mut target: actor::Actor
+    mut target: actor::Actor,
+    query: Option<String>,
 ) -> Response {
     if let Some((redis, in_topic)) = &state.redis {
         if let Ok(data) = serde_json::to_vec(&endpoint.payload) {
@@ -186,6 +329,15 @@ async fn post_relay(
         let Ok(remote_actor) = remote_actor else {
             return (StatusCode::BAD_REQUEST, "Invalid actor").into_response();
         };
+        let filter = match query.as_deref().unwrap_or_default().parse::<filter::Filter>() {
+            Ok(filter) => filter,
+            Err(e) => {
+                track_request("POST", "relay", "bad_filter");
+                return (StatusCode::BAD_REQUEST,
+                        format!("Bad filter: {e}")
+                ).into_response();
+            }
+        };
         if let Some(action_target) = action.object.and_then(|object| Actor::from_object(&object)) {
             if action_target.host == state.hostname {
                 // A sharedInbox receives the actual follow target in the
@@ -193,38 +345,66 @@ async fn post_relay(
                 target = action_target;
             }
         }
-        let priv_key = state.priv_key.clone();
-        let client = state.client.clone();
-        tokio::spawn(async move {
-            let accept_id = format!(
-                "https://{}/activity/accept/{}/{}",
-                state.hostname,
-                urlencoding::encode(&target.uri()),
-                urlencoding::encode(&remote_actor.inbox),
-            );
-            let accept = activitypub::Action {
-                jsonld_context: serde_json::Value::String("https://www.w3.org/ns/activitystreams".to_string()),
-                action_type: "Accept".to_string(),
-                actor: target.uri(),
-                to: Some(json!(remote_actor.id.clone())),
-                id: accept_id,
-                object: Some(endpoint.payload),
+        let blocked = reqwest::Url::parse(&remote_actor.inbox)
+            .ok()
+            .and_then(|inbox| inbox.host_str().map(|host| state.blocklist.is_blocked(host)))
+            .unwrap_or(false);
+        if state.approve_follows && ! blocked {
+            // Leave it to an admin to accept or reject
+            let follow = endpoint.payload.to_string();
+            return match state.database.add_pending_follow(
+                &remote_actor.id,
+                &remote_actor.inbox,
+                &target.uri(),
+                follow::is_shared_inbox(&remote_actor),
+                &follow,
+                &filter,
+            ).await {
+                Ok(()) => {
+                    tracing::info!("pending follow of {} by {}", target.uri(), remote_actor.id);
+                    track_request("POST", "relay", "follow_pending");
+                    (StatusCode::ACCEPTED,
+                     [("content-type", "application/activity+json")],
+                     "{}"
+                    ).into_response()
+                }
+                Err(e) => {
+                    tracing::error!("add_pending_follow: {}", e);
+                    track_request("POST", "relay", "follow_error");
+                    (StatusCode::INTERNAL_SERVER_ERROR,
+                     format!("{e}")
+                    ).into_response()
+                }
             };
-            let result = send::send(
-                client.as_ref(), &remote_actor.inbox,
-                &target.key_id(),
-                &priv_key,
-                &accept,
-            ).await;
+        }
+        tokio::spawn(async move {
+            if blocked {
+                match follow::send_response(&state, &target, &remote_actor.id, &remote_actor.inbox, endpoint.payload, "Reject").await {
+                    Ok(()) => {
+                        tracing::info!("rejected follow of {} by blocked {}", target.uri(), remote_actor.id);
+                        track_request("POST", "relay", "follow_blocked");
+                    }
+                    Err(e) => {
+                        tracing::error!("post reject: {}", e);
+                        track_request("POST", "relay", "follow_reject_error");
+                    }
+                }
+                return;
+            }
+
+            let result = follow::send_response(&state, &target, &remote_actor.id, &remote_actor.inbox, endpoint.payload, "Accept").await;
             match result {
                 Ok(()) => {
                     match state.database.add_follow(
                         &remote_actor.id,
                         &remote_actor.inbox,
                         &target.uri(),
+                        follow::is_shared_inbox(&remote_actor),
+                        &filter,
                     ).await {
                         Ok(()) => {
                             track_request("POST", "relay", "follow");
+                            follow::record_software(&state, &remote_actor.id, &remote_actor.inbox).await;
                         }
                         Err(e) => {
                             // duplicate key constraint
@@ -258,6 +438,12 @@ async fn post_relay(
                 target = action_target;
             }
         }
+        if let Err(e) = state.database.del_pending_follow(
+            &remote_actor.id,
+            &target.uri(),
+        ).await {
+            tracing::error!("del_pending_follow: {}", e);
+        }
         match state.database.del_follow(
             &remote_actor.id,
             &target.uri(),
@@ -365,16 +551,25 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    match cli::Cli::parse().command() {
+        cli::Command::Serve { config } =>
+            serve(config::Config::load(&config)).await,
+        cli::Command::Follows { config, command } =>
+            cli::follows(&config::Config::load(&config), command).await,
+        cli::Command::Db { config, command } =>
+            cli::db(&config::Config::load(&config), command).await,
+        cli::Command::Keys { config, command } =>
+            cli::keys(&config::Config::load(&config), command),
+    }
+}
+
+async fn serve(config: config::Config) {
     let recorder = PrometheusBuilder::new()
         .add_global_label("application", env!("CARGO_PKG_NAME"))
         .idle_timeout(MetricKindMask::ALL, Some(Duration::from_secs(600)))
         .install_recorder()
         .unwrap();
 
-    let config = config::Config::load(
-        &std::env::args().nth(1)
-            .expect("Call with config.yaml")
-    );
     let database = db::Database::connect(&config.db).await;
     let mut redis = None;
     if let Some(redis_config) = config.redis.clone() {
@@ -402,24 +597,48 @@ async fn main() {
         .pool_idle_timeout(Some(Duration::from_secs(5)))
         .build()
         .unwrap();
-    let state = State::new(config.clone(), database, redis, client);
+    let (stream_rx, stream_statuses) = stream::spawn(
+        config.streams.iter()
+            .map(stream::StreamSource::from_config)
+    );
+    let state = State::new(config.clone(), database, redis, client, stream_statuses);
 
-    let stream_rx = stream::spawn(config.streams.clone().into_iter());
-    relay::spawn(state.clone(), stream_rx);
+    state.blocklist.spawn_reload(config.blocklist.clone());
+    for domain in state.database.get_domain_blocks().await.expect("get_domain_blocks") {
+        state.blocklist.add(domain);
+    }
+    relay::spawn(state.clone(), config.delivery.clone(), stream_rx);
+    if let Some(prune_config) = config.prune_inboxes.clone() {
+        relay::spawn_prune(state.database.clone(), prune_config);
+    }
 
     let app = Router::new()
         .route("/tag/{tag}", get(get_tag_actor).post(post_tag_relay))
         .route("/instance/{instance}", get(get_instance_actor).post(post_instance_relay))
         .route("/language/{language}", get(get_language_actor).post(post_language_relay))
+        .route("/account/{account}", get(get_account_actor).post(post_account_relay))
+        .route("/tag/{tag}/language/{language}", get(get_tag_language_actor).post(post_tag_language_relay))
+        .route("/tags/{tags}", get(get_tags_actor).post(post_tags_relay))
+        .route("/keyword/{keyword}", get(get_keyword_actor).post(post_keyword_relay))
         .route("/tag/{tag}/outbox", get(outbox))
         .route("/instance/{instance}/outbox", get(outbox))
         .route("/language/{language}/outbox", get(outbox))
+        .route("/account/{account}/outbox", get(outbox))
+        .route("/tag/{tag}/language/{language}/outbox", get(outbox))
+        .route("/tags/{tags}/outbox", get(outbox))
+        .route("/keyword/{keyword}/outbox", get(outbox))
         .route("/.well-known/webfinger", get(webfinger))
         .route("/.well-known/nodeinfo", get(nodeinfo))
         .route("/api/v1/instance", get(instanceinfo))
         .route("/metrics", get(|| async move {
             recorder.render().into_response()
-        }))
+        }));
+    let app = if let Some(admin_config) = &config.admin {
+        app.nest("/admin", admin::router(admin_config.token()))
+    } else {
+        app
+    };
+    let app = app
         .with_state(state)
         .fallback_service(ServeDir::new("static"));
 
@@ -1,18 +1,39 @@
-use std::{sync::Arc, collections::{HashSet, HashMap}, time::{Duration, Instant}};
-use futures::{channel::mpsc::{channel, Sender}, StreamExt};
+use std::{sync::Arc, collections::{HashMap, HashSet}, future::Future, num::NonZeroUsize, time::{Duration, Instant}};
 use metrics::{counter, histogram};
-use serde::Deserialize;
+use serde::{de::IgnoredAny, Deserialize};
 use serde_json::json;
-use sigh::PrivateKey;
-use tokio::sync::mpsc::Receiver;
-use crate::{send, actor, state::State};
+use tokio::{sync::mpsc::Receiver, time::sleep};
+use crate::{
+    actor,
+    config::{DeliveryConfig, PruneConfig},
+    db::{self, Announcement, Database, Delivery},
+    dedup::RecentPosts,
+    error::Error,
+    filter::PostAttributes,
+    keywords::text_of_html,
+    state::State,
+    stream::{Event, EventKind},
+    worker::{Job, Workers},
+};
 
 #[derive(Deserialize)]
 struct Post<'a> {
+    pub id: Option<&'a str>,
     pub url: Option<&'a str>,
     pub uri: &'a str,
     pub tags: Option<Vec<Tag<'a>>>,
     pub language: Option<&'a str>,
+    pub edited_at: Option<&'a str>,
+    #[serde(default)]
+    pub sensitive: bool,
+    #[serde(default)]
+    pub spoiler_text: String,
+    /// HTML
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub media_attachments: Vec<IgnoredAny>,
+    pub account: Option<Account>,
 }
 
 impl Post<'_> {
@@ -24,6 +45,32 @@ impl Post<'_> {
             )
     }
 
+    /// The author as `user@host`
+    pub fn account(&self) -> Option<String> {
+        let account = self.account.as_ref()?;
+        if account.acct.contains('@') {
+            Some(account.acct.clone())
+        } else {
+            // local to the instance that streamed it
+            let host = match &account.local_domain {
+                Some(domain) => domain.clone(),
+                None => self.local_account_host()?,
+            };
+            Some(format!("{}@{}", account.acct, host))
+        }
+    }
+
+    /// The web host of the author if local to the instance that
+    /// streamed it, whose handles may use another domain
+    fn local_account_host(&self) -> Option<String> {
+        let account = self.account.as_ref()
+            .filter(|account| ! account.acct.contains('@'))?;
+        reqwest::Url::parse(account.url.as_deref()?)
+            .ok()?
+            .domain()
+            .map(str::to_lowercase)
+    }
+
     pub fn tags(&self) -> Vec<String> {
         match &self.tags {
             None =>
@@ -35,6 +82,15 @@ impl Post<'_> {
         }
     }
 
+    /// What per-follower filters look at
+    pub fn attributes(&self) -> PostAttributes<'_> {
+        PostAttributes {
+            language: self.language,
+            has_media: ! self.media_attachments.is_empty(),
+            sensitive: self.sensitive || ! self.spoiler_text.is_empty(),
+        }
+    }
+
     fn relay_target_kinds(&self) -> impl Iterator<Item = actor::ActorKind> {
         self.host()
             .into_iter()
@@ -78,6 +134,25 @@ impl Post<'_> {
                 self.language
                     .and_then(actor::ActorKind::from_language)
             )
+            .chain(
+                self.account()
+                    .and_then(|account| actor::ActorKind::from_account(&account))
+            )
+    }
+
+    /// Whether the post fits a composite actor that was looked up by
+    /// one of its tags
+    fn matches_composite(&self, kind: &actor::ActorKind) -> bool {
+        match kind {
+            actor::ActorKind::TagLanguageRelay(_, language) =>
+                self.language
+                    .and_then(actor::ActorKind::from_language)
+                    .is_some_and(|kind| kind == actor::ActorKind::LanguageRelay(language.clone())),
+            actor::ActorKind::TagsRelay(_) =>
+                true,
+            _ =>
+                false,
+        }
     }
 
     pub fn relay_targets(&self, hostname: Arc<String>) -> impl Iterator<Item = actor::Actor> {
@@ -94,147 +169,572 @@ struct Tag<'a> {
     pub name: &'a str,
 }
 
-struct Job {
-    post_url: Arc<String>,
-    actor_id: Arc<String>,
-    body: Arc<Vec<u8>>,
-    key_id: String,
-    private_key: Arc<PrivateKey>,
-    inbox_url: reqwest::Url,
+#[derive(Deserialize)]
+struct Account {
+    /// `user` for local accounts, `user@host` for remote ones
+    pub acct: String,
+    pub url: Option<String>,
+    /// The domain of local accounts' handles, as looked up by the
+    /// relay
+    #[serde(skip)]
+    pub local_domain: Option<String>,
+}
+
+/// `/api/v2/instance`
+#[derive(Deserialize)]
+struct Instance {
+    /// Used in handles, even if served from another domain
+    domain: String,
+}
+
+/// Looks up the domain of an instance's handles, which differs from
+/// `host` if that is a subdomain like `www.example.org`
+async fn fetch_local_domain(client: &reqwest::Client, host: &str) -> Result<String, Error> {
+    let instance: Instance = client.get(format!("https://{host}/api/v2/instance"))
+        .header("accept", "application/json")
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if instance.domain.is_empty() || instance.domain.contains(['/', '@']) {
+        return Err(Error::Response(format!("invalid domain {:?}", instance.domain)));
+    }
+    Ok(instance.domain.to_lowercase())
 }
 
-fn spawn_worker(client: Arc<reqwest::Client>) -> Sender<Job> {
-    let (tx, mut rx) = channel(512);
+/// How often to look for dead inboxes and old announcements
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+/// How many post URIs to remember for deduplication
+const RECENT_POSTS_CAPACITY: usize = 65536;
+/// How long a post URI is remembered for deduplication
+const RECENT_POSTS_WINDOW: Duration = Duration::from_secs(3600);
+/// How often to look for deliveries that were left in the database
+const REPLAY_INTERVAL: Duration = Duration::from_secs(300);
+/// Deliveries read from the database at once when replaying
+const REPLAY_BATCH: i64 = 1000;
+/// How long to remember announcements for retracting them on deletion
+const ANNOUNCEMENTS_RETENTION: Duration = Duration::from_secs(7 * 86400);
+/// How long the firehose waits for an instance's handle domain
+const LOCAL_DOMAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an instance's handle domain is remembered
+const LOCAL_DOMAIN_TTL: Duration = Duration::from_secs(86400);
+/// How long to use the web domain as the handle domain after a
+/// failed lookup
+const LOCAL_DOMAIN_RETRY: Duration = Duration::from_secs(3600);
+/// How long the firehose waits for a query before going without
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the firehose stops querying after a query failed
+const QUERY_BREAK: Duration = Duration::from_secs(30);
+
+/// Periodically unfollow inboxes that have been failing for too long
+pub fn spawn_prune(database: Database, config: PruneConfig) {
+    let failures = config.failures.try_into().unwrap_or(i32::MAX);
+    let failing_for = Duration::from_secs(86400 * u64::from(config.days));
 
     tokio::spawn(async move {
-        let mut errors = 0u32;
-        let mut last_request = None;
-
-        while let Some(Job { post_url, actor_id, key_id, private_key, body, inbox_url }) = rx.next().await {
-            if errors > 0 && last_request.is_some_and(|last_request: Instant|
-                last_request.elapsed() < Duration::from_secs(10) * errors
-            ) {
-                // there have been errors, skip for time proportional
-                // to the number of subsequent errors
-                tracing::trace!("skip {} from {} to {}", post_url, actor_id, inbox_url);
-                continue;
+        loop {
+            match database.prune_dead_inboxes(failures, failing_for).await {
+                Ok(pruned) => {
+                    for (id, inbox, actor) in pruned {
+                        tracing::warn!("pruned follow of {} by {} with dead inbox {}", actor, id, inbox);
+                        counter!("relay_follows_pruned_total")
+                            .increment(1);
+                    }
+                }
+                Err(e) =>
+                    tracing::error!("prune_dead_inboxes: {}", e),
             }
 
-            tracing::debug!("relay {} from {} to {}", post_url, actor_id, inbox_url);
-            last_request = Some(Instant::now());
-            if let Err(e) = send::send_raw(
-                &client, inbox_url.as_str(),
-                &key_id, &private_key, body
-            ).await {
-                tracing::error!("relay::send {:?}", e);
-                errors = errors.saturating_add(1);
-            } else {
-                // success
-                errors = 0;
-                systemd::daemon::notify(
-                    false, [
-                        (systemd::daemon::STATE_WATCHDOG, "1")
-                    ].iter()
-                ).unwrap();
-            }
+            sleep(PRUNE_INTERVAL).await;
         }
-
-        panic!("Worker dead");
     });
-
-    tx
 }
 
-pub fn spawn(
-    state: State,
-    mut stream_rx: Receiver<String>
-) {
+/// Queue deliveries from the database: those that were still pending
+/// when the process stopped, and those that did not fit into a full
+/// queue since
+fn spawn_replay(state: State, workers: Workers) {
     tokio::spawn(async move {
-        let mut workers = HashMap::new();
+        loop {
+            replay(&state, &workers).await;
+            sleep(REPLAY_INTERVAL).await;
+        }
+    });
+}
 
-        while let Some(data) = stream_rx.recv().await {
-            let t1 = Instant::now();
-            let post: Post = match serde_json::from_str(&data) {
-                Ok(post) => post,
-                Err(e) => {
-                    tracing::error!("parse error: {}", e);
-                    tracing::trace!("data: {}", data);
-                    continue;
+async fn replay(state: &State, workers: &Workers) {
+    workers.start_replay();
+    let mut after = 0;
+    loop {
+        let deliveries = match state.database.get_deliveries(after, REPLAY_BATCH).await {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                tracing::error!("get_deliveries: {}", e);
+                return;
+            }
+        };
+        let Some(last) = deliveries.last() else { return; };
+        after = last.id;
+        tracing::debug!("replaying up to {} pending deliveries", deliveries.len());
+
+        for Delivery { id, inbox, actor, post_url, body, attempts } in deliveries {
+            let (Ok(inbox_url), Some(actor)) = (reqwest::Url::parse(&inbox), actor::Actor::from_uri(&actor)) else {
+                if let Err(e) = state.database.del_delivery(id).await {
+                    tracing::error!("del_delivery: {}", e);
                 }
+                continue;
+            };
+            let job = Job {
+                delivery_id: Some(id),
+                attempts: attempts.try_into().unwrap_or(0),
+                post_url: Arc::new(post_url),
+                actor_id: Arc::new(actor.uri()),
+                body: Arc::new(body),
+                key_id: actor.key_id(),
+                private_key: state.priv_key.clone(),
+                inbox_url,
             };
-            let post_url = if let Some(url) = post.url {
-                Arc::new(url.to_string())
-            } else {
-                // skip reposts
-                counter!("relay_posts_total", "action" => "skip")
+            // A full queue, like that of a paused host, must not hold
+            // up the others. Its deliveries wait for the next pass.
+            if workers.enqueue(job) {
+                counter!("relay_deliveries_total", "action" => "replay")
                     .increment(1);
-                continue;
+            }
+        }
+    }
+}
+
+/// Keeps the firehose going while the database is down or slow.
+///
+/// A query that fails or times out makes the following ones be
+/// skipped for a while, so that posts are delivered from memory
+/// instead of each waiting for the pool.
+struct Breaker {
+    timeout: Duration,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn new(timeout: Duration) -> Self {
+        Breaker {
+            timeout,
+            open_until: None,
+        }
+    }
+
+    /// The result of `query`, or `None` if it was skipped or failed
+    async fn call<T>(
+        &mut self,
+        name: &'static str,
+        query: impl Future<Output = Result<T, db::Error>>,
+    ) -> Option<T> {
+        if self.open_until.is_some_and(|until| Instant::now() < until) {
+            counter!("relay_queries_skipped_total", "query" => name)
+                .increment(1);
+            return None;
+        }
+        match tokio::time::timeout(self.timeout, query).await {
+            Ok(Ok(result)) => {
+                self.open_until = None;
+                Some(result)
+            }
+            Ok(Err(e)) => {
+                tracing::error!("{}: {}", name, e);
+                self.open(name);
+                None
+            }
+            Err(_) => {
+                tracing::error!("{}: timed out", name);
+                self.open(name);
+                None
+            }
+        }
+    }
+
+    fn open(&mut self, name: &'static str) {
+        tracing::warn!("skipping database queries for {:?} after {} failed", QUERY_BREAK, name);
+        self.open_until = Some(Instant::now() + QUERY_BREAK);
+    }
+}
+
+struct Relay {
+    state: State,
+    workers: Workers,
+    recent_posts: RecentPosts,
+    breaker: Breaker,
+    /// Handle domains by web host, and until when they are valid
+    local_domains: HashMap<String, (String, Instant)>,
+}
+
+impl Relay {
+    /// The domain in the handles of local accounts on `host`
+    async fn local_domain(&mut self, host: String) -> String {
+        if let Some((domain, until)) = self.local_domains.get(&host) {
+            if *until > Instant::now() {
+                return domain.clone();
+            }
+        }
+        let (domain, ttl) = match tokio::time::timeout(LOCAL_DOMAIN_TIMEOUT, fetch_local_domain(&self.state.client, &host)).await {
+            Ok(Ok(domain)) =>
+                (domain, LOCAL_DOMAIN_TTL),
+            Ok(Err(e)) => {
+                tracing::warn!("local domain of {}: {}", host, e);
+                (host.clone(), LOCAL_DOMAIN_RETRY)
+            }
+            Err(_) => {
+                tracing::warn!("local domain of {}: timed out", host);
+                (host.clone(), LOCAL_DOMAIN_RETRY)
+            }
+        };
+        self.local_domains.insert(host, (domain.clone(), Instant::now() + ttl));
+        domain
+    }
+
+    /// Persist an activity for all of its inboxes at once, then queue
+    /// it for the workers of the inbox hosts. If it cannot be
+    /// persisted in time it is still attempted, but won't survive a
+    /// restart.
+    async fn deliver(
+        &mut self,
+        inbox_urls: Vec<reqwest::Url>,
+        actor: &actor::Actor,
+        post_url: &Arc<String>,
+        body: &Arc<Vec<u8>>,
+    ) {
+        if inbox_urls.is_empty() {
+            return;
+        }
+        let actor_id = Arc::new(actor.uri());
+        let inboxes = inbox_urls.iter()
+            .map(|inbox_url| inbox_url.to_string())
+            .collect::<Vec<_>>();
+        let delivery_ids = match self.breaker.call("add_deliveries", self.state.database.add_deliveries(&inboxes, &actor_id, post_url, body)).await {
+            Some(delivery_ids) => delivery_ids.into_iter()
+                .map(Some)
+                .collect(),
+            None => vec![None; inbox_urls.len()],
+        };
+
+        for (inbox_url, delivery_id) in inbox_urls.into_iter().zip(delivery_ids) {
+            // Create queue item.
+            let job = Job {
+                delivery_id,
+                attempts: 0,
+                post_url: post_url.clone(),
+                actor_id: actor_id.clone(),
+                body: body.clone(),
+                key_id: actor.key_id(),
+                private_key: self.state.priv_key.clone(),
+                inbox_url,
             };
-            let mut seen_actors = HashSet::new();
-            let mut seen_inboxes = HashSet::new();
-            let published = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-            for actor in post.relay_targets(state.hostname.clone()) {
-                if seen_actors.contains(&actor) {
+            self.workers.enqueue(job);
+        }
+    }
+
+    /// Announce a new post to all inboxes following its relay targets
+    async fn relay_post(&mut self, stream: &str, data: &str) {
+        let mut post: Post = match serde_json::from_str(data) {
+            Ok(post) => post,
+            Err(e) => {
+                tracing::error!("parse error: {}", e);
+                tracing::trace!("data: {}", data);
+                return;
+            }
+        };
+        let post_url = if let Some(url) = post.url {
+            Arc::new(url.to_string())
+        } else {
+            // skip reposts
+            counter!("relay_posts_total", "action" => "skip")
+                .increment(1);
+            return;
+        };
+        if post.host().is_some_and(|host| self.state.blocklist.is_blocked(&host)) {
+            counter!("relay_posts_total", "action" => "blocked")
+                .increment(1);
+            return;
+        }
+        if ! self.recent_posts.insert(post.uri) {
+            // already relayed from another stream
+            counter!("relay_posts_total", "action" => "duplicate")
+                .increment(1);
+            return;
+        }
+        let Ok(post_url_url) = reqwest::Url::parse(&post_url) else { return; };
+        if let Some(host) = post.local_account_host() {
+            // so that it matches the handle seen on other instances
+            let domain = self.local_domain(host).await;
+            if let Some(account) = &mut post.account {
+                account.local_domain = Some(domain);
+            }
+        }
+        let mut seen_actors = HashSet::new();
+        let mut seen_inboxes = HashSet::new();
+        let attributes = post.attributes();
+        let published = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let mut targets = post.relay_targets(self.state.hostname.clone())
+            .collect::<Vec<_>>();
+        let composites = targets.iter()
+            .filter_map(|actor| match &actor.kind {
+                actor::ActorKind::TagRelay(tag) => Some(tag),
+                _ => None,
+            })
+            .flat_map(|tag| self.state.database.get_composite_actors(tag))
+            .filter(|actor| post.matches_composite(&actor.kind))
+            .collect::<Vec<_>>();
+        targets.extend(composites);
+        let keywords = self.state.database.get_keywords();
+        if ! keywords.is_empty() {
+            targets.extend(keywords.matches(&text_of_html(&post.content)));
+        }
+        for actor in targets {
+            if seen_actors.contains(&actor) {
+                continue;
+            }
+
+            let actor_id = actor.uri();
+            let body = json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Announce",
+                "actor": &actor_id,
+                "published": &published,
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": &post.uri,
+                "id": announce_id(&self.state.hostname, &post_url),
+            });
+            let body = Arc::new(
+                serde_json::to_vec(&body)
+                    .unwrap()
+            );
+            let mut announced_inboxes = vec![];
+            let mut inbox_urls = vec![];
+            for subscriber in self.state.database.get_subscribers(&actor_id).iter() {
+                // Not marked as seen, another follow of the same inbox
+                // may want it.
+                if ! subscriber.filter.matches(&attributes) {
                     continue;
                 }
 
-                let actor_id = Arc::new(actor.uri());
-                let announce_id = format!("https://{}/announce/{}", state.hostname, urlencoding::encode(&post_url));
-                let body = json!({
-                    "@context": "https://www.w3.org/ns/activitystreams",
-                    "type": "Announce",
-                    "actor": *actor_id,
-                    "published": &published,
-                    "to": ["https://www.w3.org/ns/activitystreams#Public"],
-                    "object": &post.uri,
-                    "id": announce_id,
-                });
-                let Ok(post_url_url) = reqwest::Url::parse(&post_url) else { continue; };
-                let body = Arc::new(
-                    serde_json::to_vec(&body)
-                        .unwrap()
-                );
-                for inbox in state.database.get_following_inboxes(&actor_id).await.unwrap() {
-                    let Ok(inbox_url) = reqwest::Url::parse(&inbox) else { continue; };
-
-                    // Avoid duplicate processing.
-                    if seen_inboxes.contains(&inbox) {
-                        continue;
-                    }
-                    seen_inboxes.insert(inbox);
+                let inbox = subscriber.inbox.clone();
+                let Ok(inbox_url) = reqwest::Url::parse(&inbox) else { continue; };
 
-                    // Prevent relaying back to the originating instance.
-                    if inbox_url.host_str() == post_url_url.host_str() {
-                        continue;
-                    }
+                // Avoid duplicate processing.
+                if seen_inboxes.contains(&inbox) {
+                    continue;
+                }
 
-                    // Lookup/create worker queue per inbox.
-                    let tx = workers.entry(inbox_url.host_str().unwrap_or("").to_string())
-                        .or_insert_with(|| spawn_worker(state.client.clone()));
-                    // Create queue item.
-                    let job = Job {
-                        post_url: post_url.clone(),
-                        actor_id: actor_id.clone(),
-                        body: body.clone(),
-                        key_id: actor.key_id(),
-                        private_key: state.priv_key.clone(),
-                        inbox_url,
-                    };
-                    // Enqueue job for worker.
-                    let _ = tx.try_send(job);
+                // Prevent relaying back to the originating instance.
+                if inbox_url.host_str() == post_url_url.host_str() {
+                    seen_inboxes.insert(inbox);
+                    continue;
                 }
 
-                seen_actors.insert(actor);
+                // Followers that got blocked after following
+                if inbox_url.host_str().is_some_and(|host| self.state.blocklist.is_blocked(host)) {
+                    seen_inboxes.insert(inbox);
+                    continue;
+                }
+
+                inbox_urls.push(inbox_url);
+                seen_inboxes.insert(inbox.clone());
+                announced_inboxes.push(inbox);
             }
-            if seen_inboxes.is_empty() {
-                counter!("relay_posts_total", "action" => "no_relay")
-                    .increment(1);
-            } else {
-                counter!("relay_posts_total", "action" => "relay")
-                    .increment(1);
+            self.deliver(inbox_urls, &actor, &post_url, &body).await;
+
+            if let Some(status_id) = post.id.filter(|_| ! announced_inboxes.is_empty()) {
+                let announcement = Announcement {
+                    post_url: post_url.to_string(),
+                    uri: post.uri.to_string(),
+                    actor: actor_id,
+                    inboxes: announced_inboxes,
+                };
+                self.breaker.call("add_announcement", self.state.database.add_announcement(stream, status_id, &announcement)).await;
+            }
+
+            seen_actors.insert(actor);
+        }
+        if seen_inboxes.is_empty() {
+            counter!("relay_posts_total", "action" => "no_relay")
+                .increment(1);
+        } else {
+            counter!("relay_posts_total", "action" => "relay")
+                .increment(1);
+        }
+    }
+
+    /// Announce an edited post again to all inboxes that received the
+    /// original.
+    ///
+    /// Receivers only accept `Update`s from the author's own server,
+    /// so the best a relay can do is a fresh `Announce` that prompts
+    /// them to look at the post again.
+    async fn relay_edit(&mut self, stream: &str, data: &str) {
+        let post: Post = match serde_json::from_str(data) {
+            Ok(post) => post,
+            Err(e) => {
+                tracing::error!("parse error: {}", e);
+                tracing::trace!("data: {}", data);
+                return;
+            }
+        };
+        let (Some(status_id), Some(edited_at)) = (post.id, post.edited_at) else {
+            return;
+        };
+        if ! self.recent_posts.insert(&format!("{}#{}", post.uri, edited_at)) {
+            // already relayed from another stream
+            counter!("relay_edits_total", "action" => "duplicate")
+                .increment(1);
+            return;
+        }
+        let Some(announcements) = self.breaker.call("get_announcements", self.state.database.get_announcements(stream, status_id)).await else {
+            return;
+        };
+        if announcements.is_empty() {
+            counter!("relay_edits_total", "action" => "no_relay")
+                .increment(1);
+            return;
+        }
+
+        let published = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        for Announcement { post_url, uri, actor: actor_id, inboxes } in announcements {
+            let Some(actor) = actor::Actor::from_uri(&actor_id) else { continue; };
+            let body = json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Announce",
+                "actor": &actor_id,
+                "published": &published,
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": &uri,
+                "id": format!(
+                    "{}#edited-{}",
+                    announce_id(&self.state.hostname, &post_url),
+                    urlencoding::encode(edited_at),
+                ),
+            });
+            let body = Arc::new(
+                serde_json::to_vec(&body)
+                    .unwrap()
+            );
+            let post_url = Arc::new(post_url);
+            let inbox_urls = inboxes.iter()
+                .filter_map(|inbox| reqwest::Url::parse(inbox).ok())
+                .collect();
+            self.deliver(inbox_urls, &actor, &post_url, &body).await;
+        }
+        counter!("relay_edits_total", "action" => "relay")
+            .increment(1);
+    }
+
+    /// Retract a deleted post from all inboxes that it was announced to
+    async fn relay_delete(&mut self, stream: &str, status_id: &str) {
+        let Some(announcements) = self.breaker.call("take_announcements", self.state.database.take_announcements(stream, status_id)).await else {
+            return;
+        };
+        if announcements.is_empty() {
+            counter!("relay_deletes_total", "action" => "no_relay")
+                .increment(1);
+            return;
+        }
+
+        for Announcement { post_url, uri, actor: actor_id, inboxes } in announcements {
+            let Some(actor) = actor::Actor::from_uri(&actor_id) else { continue; };
+            let body = json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "type": "Undo",
+                "actor": &actor_id,
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "object": {
+                    "type": "Announce",
+                    "actor": &actor_id,
+                    "object": &uri,
+                    "id": announce_id(&self.state.hostname, &post_url),
+                },
+                "id": format!(
+                    "https://{}/undo/{}/{}",
+                    self.state.hostname,
+                    urlencoding::encode(&actor_id),
+                    urlencoding::encode(&post_url),
+                ),
+            });
+            let body = Arc::new(
+                serde_json::to_vec(&body)
+                    .unwrap()
+            );
+            let post_url = Arc::new(post_url);
+            let inbox_urls = inboxes.iter()
+                .filter_map(|inbox| reqwest::Url::parse(inbox).ok())
+                .collect();
+            self.deliver(inbox_urls, &actor, &post_url, &body).await;
+        }
+        counter!("relay_deletes_total", "action" => "relay")
+            .increment(1);
+    }
+}
+
+fn announce_id(hostname: &str, post_url: &str) -> String {
+    format!("https://{}/announce/{}", hostname, urlencoding::encode(post_url))
+}
+
+pub fn spawn(
+    state: State,
+    delivery_config: DeliveryConfig,
+    mut stream_rx: Receiver<Event>
+) {
+    let database = state.database.clone();
+    tokio::spawn(async move {
+        loop {
+            match database.prune_announcements(ANNOUNCEMENTS_RETENTION).await {
+                Ok(count) =>
+                    tracing::debug!("pruned {} announcements", count),
+                Err(e) =>
+                    tracing::error!("prune_announcements: {}", e),
+            }
+            match database.prune_delivery_bodies().await {
+                Ok(count) =>
+                    tracing::debug!("pruned {} delivery bodies", count),
+                Err(e) =>
+                    tracing::error!("prune_delivery_bodies: {}", e),
+            }
+
+            sleep(PRUNE_INTERVAL).await;
+        }
+    });
+
+    let workers = Workers::new(delivery_config, state.client.clone(), state.database.clone());
+    spawn_replay(state.clone(), workers.clone());
+
+    tokio::spawn(async move {
+        let mut relay = Relay {
+            workers,
+            recent_posts: RecentPosts::new(
+                NonZeroUsize::new(RECENT_POSTS_CAPACITY).unwrap(),
+                RECENT_POSTS_WINDOW,
+            ),
+            breaker: Breaker::new(QUERY_TIMEOUT),
+            local_domains: HashMap::new(),
+            state,
+        };
+
+        while let Some(Event { stream, kind }) = stream_rx.recv().await {
+            let t1 = Instant::now();
+            match kind {
+                EventKind::Update(data) => {
+                    relay.relay_post(&stream, &data).await;
+                    let t2 = Instant::now();
+                    histogram!("relay_post_duration").record(t2 - t1);
+                }
+                EventKind::StatusUpdate(data) => {
+                    relay.relay_edit(&stream, &data).await;
+                    let t2 = Instant::now();
+                    histogram!("relay_edit_duration").record(t2 - t1);
+                }
+                EventKind::Delete(status_id) => {
+                    relay.relay_delete(&stream, &status_id).await;
+                    let t2 = Instant::now();
+                    histogram!("relay_delete_duration").record(t2 - t1);
+                }
             }
-            let t2 = Instant::now();
-            histogram!("relay_post_duration").record(t2 - t1);
         }
     });
 }
@@ -247,12 +747,19 @@ mod test {
     #[test]
     fn post_relay_kind() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: Some(vec![Tag {
                 name: "foo",
             }]),
             language: Some("en"),
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
@@ -261,15 +768,109 @@ mod test {
         assert_eq!(kinds.next(), None);
     }
 
+    #[test]
+    fn post_attributes() {
+        let post: Post = serde_json::from_str(r#"{
+            "uri": "http://example.com/post/1",
+            "language": "de",
+            "sensitive": false,
+            "spoiler_text": "\"spoilers\"",
+            "media_attachments": [{"type": "image", "url": "http://example.com/1.png"}]
+        }"#).unwrap();
+        let attributes = post.attributes();
+        assert_eq!(attributes.language, Some("de"));
+        assert!(attributes.has_media);
+        assert!(attributes.sensitive);
+
+        let post: Post = serde_json::from_str(r#"{"uri": "http://example.com/post/1"}"#).unwrap();
+        let attributes = post.attributes();
+        assert!(! attributes.has_media);
+        assert!(! attributes.sensitive);
+    }
+
+    #[test]
+    fn post_relay_kind_account() {
+        let post: Post = serde_json::from_str(r#"{
+            "url": "http://example.com/post/1",
+            "uri": "http://example.com/post/1",
+            "account": {"acct": "Alice", "url": "https://example.com/@Alice"}
+        }"#).unwrap();
+        let mut kinds = post.relay_target_kinds();
+        assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
+        assert_eq!(kinds.next(), Some(ActorKind::AccountRelay("alice@example.com".to_string())));
+        assert_eq!(kinds.next(), None);
+
+        let post: Post = serde_json::from_str(r#"{
+            "url": "http://example.com/post/1",
+            "uri": "http://example.com/post/1",
+            "account": {"acct": "bob@example.org", "url": "https://www.example.org/@bob"}
+        }"#).unwrap();
+        assert_eq!(post.relay_target_kinds().last(), Some(ActorKind::AccountRelay("bob@example.org".to_string())));
+    }
+
+    #[test]
+    fn post_relay_kind_split_domain() {
+        // seen on another instance
+        let remote: Post = serde_json::from_str(r#"{
+            "url": "https://www.example.org/@bob/1",
+            "uri": "https://www.example.org/users/bob/statuses/1",
+            "account": {"acct": "bob@example.org", "url": "https://www.example.org/@bob"}
+        }"#).unwrap();
+        assert_eq!(remote.local_account_host(), None);
+
+        // streamed from his own instance
+        let mut local: Post = serde_json::from_str(r#"{
+            "url": "https://www.example.org/@bob/1",
+            "uri": "https://www.example.org/users/bob/statuses/1",
+            "account": {"acct": "bob", "url": "https://www.example.org/@bob"}
+        }"#).unwrap();
+        assert_eq!(local.local_account_host().as_deref(), Some("www.example.org"));
+        local.account.as_mut().unwrap().local_domain = Some("example.org".to_string());
+
+        let kind = Some(ActorKind::AccountRelay("bob@example.org".to_string()));
+        assert_eq!(remote.relay_target_kinds().last(), kind);
+        assert_eq!(local.relay_target_kinds().last(), kind);
+    }
+
+    #[test]
+    fn post_matches_composite() {
+        let post = Post {
+            id: None,
+            url: Some("http://example.com/post/1"),
+            uri: "http://example.com/post/1",
+            tags: Some(vec![Tag {
+                name: "rust",
+            }]),
+            language: Some("en-GB"),
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
+        };
+        assert!(post.matches_composite(&ActorKind::from_tag_language("rust", "en").unwrap()));
+        assert!(! post.matches_composite(&ActorKind::from_tag_language("rust", "de").unwrap()));
+        assert!(post.matches_composite(&ActorKind::from_tags("rust+rustlang").unwrap()));
+        assert!(! post.matches_composite(&ActorKind::from_tag("rust")));
+    }
+
     #[test]
     fn post_relay_kind_empty() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: Some(vec![Tag {
                 name: "",
             }]),
             language: None,
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
@@ -279,12 +880,19 @@ mod test {
     #[test]
     fn post_relay_kind_numeric() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: Some(vec![Tag {
                 name: "23",
             }]),
             language: None,
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
@@ -295,12 +903,19 @@ mod test {
     #[test]
     fn post_relay_kind_date() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: Some(vec![Tag {
                 name: "dd1302",
             }]),
             language: None,
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
@@ -312,12 +927,19 @@ mod test {
     #[test]
     fn post_relay_kind_ja() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: Some(vec![Tag {
                 name: "スコティッシュ・フォールド・ロングヘアー",
             }]),
             language: Some("ja"),
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
@@ -329,10 +951,17 @@ mod test {
     #[test]
     fn post_relay_language_long() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: None,
             language: Some("de_CH"),
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
@@ -343,13 +972,33 @@ mod test {
     #[test]
     fn post_relay_language_invalid() {
         let post = Post {
+            id: None,
             url: Some("http://example.com/post/1"),
             uri: "http://example.com/post/1",
             tags: None,
             language: Some("23q"),
+            edited_at: None,
+            sensitive: false,
+            spoiler_text: String::new(),
+            content: String::new(),
+            media_attachments: vec![],
+            account: None,
         };
         let mut kinds = post.relay_target_kinds();
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
         assert_eq!(kinds.next(), None);
     }
+
+    #[tokio::test]
+    async fn breaker() {
+        let mut breaker = Breaker::new(Duration::from_millis(10));
+        assert_eq!(breaker.call("ok", async { Ok(1) }).await, Some(1));
+        assert_eq!(breaker.call("slow", std::future::pending::<Result<i32, db::Error>>()).await, None);
+        // skipped without polling
+        assert_eq!(breaker.call("ok", async { Ok(2) }).await, None);
+        breaker.open_until = Some(Instant::now());
+        assert_eq!(breaker.call("ok", async { Ok(3) }).await, Some(3));
+        assert_eq!(breaker.call("failing", async { Err::<i32, _>(db::Error::Sqlite(rusqlite::Error::QueryReturnedNoRows)) }).await, None);
+        assert!(breaker.open_until.is_some());
+    }
 }
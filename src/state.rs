@@ -3,7 +3,7 @@ use axum::{
 };
 use sigh::{PrivateKey, PublicKey};
 use std::sync::Arc;
-use crate::{config::Config, db::Database, actor_cache::ActorCache};
+use crate::{config::Config, db::Database, actor_cache::ActorCache, blocklist::Blocklist, stream::StreamStatuses};
 
 #[derive(Clone)]
 pub struct State {
@@ -14,6 +14,9 @@ pub struct State {
     pub hostname: Arc<String>,
     pub priv_key: Arc<PrivateKey>,
     pub pub_key: Arc<PublicKey>,
+    pub blocklist: Blocklist,
+    pub approve_follows: bool,
+    pub stream_statuses: StreamStatuses,
 }
 
 
@@ -24,9 +27,10 @@ impl FromRef<State> for Arc<reqwest::Client> {
 }
 
 impl State {
-    pub fn new(config: Config, database: Database, redis: Option<(redis::aio::ConnectionManager, String)>, client: reqwest::Client) -> Self {
+    pub fn new(config: Config, database: Database, redis: Option<(redis::aio::ConnectionManager, String)>, client: reqwest::Client, stream_statuses: StreamStatuses) -> Self {
         let priv_key = Arc::new(config.priv_key());
         let pub_key = Arc::new(config.pub_key());
+        let blocklist = Blocklist::load(&config.blocklist);
         State {
             database,
             redis: redis.map(|(connection, in_topic)| (connection, Arc::new(in_topic))),
@@ -35,6 +39,9 @@ impl State {
             hostname: Arc::new(config.hostname),
             priv_key,
             pub_key,
+            blocklist,
+            approve_follows: config.approve_follows,
+            stream_statuses,
         }
     }
 }
@@ -1,22 +1,360 @@
-use std::time::Duration;
-use futures::{Stream, StreamExt};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, SinkExt, Stream, StreamExt};
 use eventsource_stream::Eventsource;
+use metrics::{counter, gauge};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use tokio::{
     sync::mpsc::{channel, Receiver},
     time::sleep,
 };
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::header::AUTHORIZATION, Message},
+};
+use crate::config::{StreamConfig, StreamFilter, StreamType};
+
+/// Delay before the first reconnect, doubled with every failure
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+/// Upper bound for the reconnect delay
+const RECONNECT_MAX: Duration = Duration::from_secs(300);
+
+/// A streaming API event, tagged with the name of its source
+#[derive(Debug)]
+pub struct Event {
+    pub stream: Arc<String>,
+    pub kind: EventKind,
+}
+
+#[derive(Debug)]
+pub enum EventKind {
+    /// A new status as JSON
+    Update(String),
+    /// An edited status as JSON
+    StatusUpdate(String),
+    /// The ID of a deleted status
+    Delete(String),
+}
+
+impl EventKind {
+    fn from_event(event: &str, data: String) -> Option<Self> {
+        match event {
+            "update" => Some(EventKind::Update(data)),
+            "status.update" => Some(EventKind::StatusUpdate(data)),
+            "delete" => Some(EventKind::Delete(data)),
+            _ => None,
+        }
+    }
+}
+
+/// A Mastodon streaming API endpoint to consume posts from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Server-sent events, one stream per URL
+    EventSource {
+        url: String,
+    },
+    /// The multiplexed WebSocket protocol at `/api/v1/streaming`
+    WebSocket {
+        url: String,
+        subscriptions: Vec<Subscription>,
+    },
+}
+
+/// A stream of the WebSocket protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subscription {
+    Public,
+    PublicLocal,
+    Hashtag(String),
+}
+
+impl Subscription {
+    /// Parses `public`, `public:local` and `hashtag:<tag>`
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "public" =>
+                Some(Subscription::Public),
+            "public:local" =>
+                Some(Subscription::PublicLocal),
+            _ =>
+                s.strip_prefix("hashtag:")
+                    .filter(|tag| ! tag.is_empty())
+                    .map(|tag| Subscription::Hashtag(tag.to_string())),
+        }
+    }
+
+    fn message(&self) -> serde_json::Value {
+        match self {
+            Subscription::Public =>
+                json!({ "type": "subscribe", "stream": "public" }),
+            Subscription::PublicLocal =>
+                json!({ "type": "subscribe", "stream": "public:local" }),
+            Subscription::Hashtag(tag) =>
+                json!({ "type": "subscribe", "stream": "hashtag", "tag": tag }),
+        }
+    }
+}
+
+impl Source {
+    /// `ws://` and `wss://` URLs select the WebSocket protocol. Their
+    /// `stream` (`public`, `public:local`) and `tag` query parameters
+    /// are turned into subscriptions, defaulting to `public`.
+    ///
+    /// Anything else is consumed as server-sent events.
+    pub fn from_url(url: &str) -> Self {
+        let Ok(mut ws_url) = reqwest::Url::parse(url) else {
+            return Source::EventSource { url: url.to_string() };
+        };
+        if ws_url.scheme() != "ws" && ws_url.scheme() != "wss" {
+            return Source::EventSource { url: url.to_string() };
+        }
+
+        let mut subscriptions = vec![];
+        let mut query = vec![];
+        for (key, value) in ws_url.query_pairs() {
+            match (key.as_ref(), value.as_ref()) {
+                ("stream", "public") =>
+                    subscriptions.push(Subscription::Public),
+                ("stream", "public:local") =>
+                    subscriptions.push(Subscription::PublicLocal),
+                ("tag", tag) =>
+                    subscriptions.push(Subscription::Hashtag(tag.to_string())),
+                _ =>
+                    query.push((key.into_owned(), value.into_owned())),
+            }
+        }
+        if subscriptions.is_empty() {
+            subscriptions.push(Subscription::Public);
+        }
+        if query.is_empty() {
+            ws_url.set_query(None);
+        } else {
+            ws_url.query_pairs_mut()
+                .clear()
+                .extend_pairs(query);
+        }
+
+        Source::WebSocket {
+            url: ws_url.to_string(),
+            subscriptions,
+        }
+    }
+
+    fn url(&self) -> &str {
+        match self {
+            Source::EventSource { url } => url,
+            Source::WebSocket { url, .. } => url,
+        }
+    }
+
+    /// The URL without any query string that may contain secrets,
+    /// for use in logs and metrics
+    pub fn name(&self) -> String {
+        let url = self.url();
+        match url.split_once('?') {
+            Some((name, _)) => name.to_string(),
+            None => url.to_string(),
+        }
+    }
+
+    /// Removes the `access_token` query parameter from the URL
+    fn take_access_token(&mut self) -> Option<String> {
+        let url = match self {
+            Source::EventSource { url } => url,
+            Source::WebSocket { url, .. } => url,
+        };
+        let mut parsed = reqwest::Url::parse(url).ok()?;
+        let mut access_token = None;
+        let query = parsed.query_pairs()
+            .filter_map(|(key, value)| if key == "access_token" {
+                access_token = Some(value.into_owned());
+                None
+            } else {
+                Some((key.into_owned(), value.into_owned()))
+            })
+            .collect::<Vec<_>>();
+        access_token.as_ref()?;
+        if query.is_empty() {
+            parsed.set_query(None);
+        } else {
+            parsed.query_pairs_mut()
+                .clear()
+                .extend_pairs(query);
+        }
+        *url = parsed.to_string();
+        access_token
+    }
+}
+
+/// A configured source with its credentials and filters
+pub struct StreamSource {
+    pub source: Source,
+    pub access_token: Option<String>,
+    pub filter: StreamFilter,
+}
+
+impl StreamSource {
+    pub fn from_config(config: &StreamConfig) -> Self {
+        let mut stream_source = match config {
+            StreamConfig::Url(url) => StreamSource {
+                source: Source::from_url(url),
+                access_token: None,
+                filter: StreamFilter::default(),
+            },
+            StreamConfig::Detailed(config) => {
+                let mut url = reqwest::Url::parse(&config.url)
+                    .expect("stream url");
+                let scheme = match (config.stream_type, url.scheme()) {
+                    (Some(StreamType::WebSocket), "https") => Some("wss"),
+                    (Some(StreamType::WebSocket), "http") => Some("ws"),
+                    (Some(StreamType::Sse), "wss") => Some("https"),
+                    (Some(StreamType::Sse), "ws") => Some("http"),
+                    _ => None,
+                };
+                if let Some(scheme) = scheme {
+                    url.set_scheme(scheme)
+                        .expect("stream url scheme");
+                }
+                let mut source = Source::from_url(url.as_str());
+                if let Source::WebSocket { subscriptions, .. } = &mut source {
+                    if ! config.subscriptions.is_empty() {
+                        *subscriptions = config.subscriptions.iter()
+                            .map(|s| Subscription::parse(s)
+                                 .unwrap_or_else(|| panic!("invalid stream subscription: {s}")))
+                            .collect();
+                    }
+                }
+                let access_token = config.access_token_file.as_ref()
+                    .map(|access_token_file| {
+                        std::fs::read_to_string(access_token_file)
+                            .expect("read access_token_file")
+                            .trim()
+                            .to_string()
+                    });
+                StreamSource {
+                    source,
+                    access_token,
+                    filter: config.filter.clone(),
+                }
+            }
+        };
+        // Move any token out of the URL so that it ends up in neither
+        // logs nor error messages.
+        if let Some(access_token) = stream_source.source.take_access_token() {
+            stream_source.access_token.get_or_insert(access_token);
+        }
+        stream_source
+    }
+}
+
+/// Post fields that are needed by [`StreamFilter`]
+#[derive(Deserialize)]
+struct FilterPost {
+    language: Option<String>,
+    #[serde(default)]
+    tags: Vec<FilterTag>,
+}
+
+#[derive(Deserialize)]
+struct FilterTag {
+    name: String,
+}
+
+impl StreamFilter {
+    fn is_empty(&self) -> bool {
+        self.languages.is_none() && self.tags.is_none()
+    }
+
+    fn matches(&self, post: &FilterPost) -> bool {
+        if let Some(languages) = &self.languages {
+            let Some(language) = &post.language else {
+                return false;
+            };
+            if ! languages.iter().any(|l| l.eq_ignore_ascii_case(language)) {
+                return false;
+            }
+        }
+        if let Some(tags) = &self.tags {
+            if ! post.tags.iter().any(|tag| tags.iter().any(|t| t.eq_ignore_ascii_case(&tag.name))) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Health of one source stream
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamStatus {
+    pub name: String,
+    pub connected: bool,
+    pub connected_since: Option<DateTime<Utc>>,
+    pub last_event: Option<DateTime<Utc>>,
+    /// Events received since start
+    pub events: u64,
+    /// Consecutive connection failures
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Shared health of all source streams
+#[derive(Clone, Default)]
+pub struct StreamStatuses(Arc<Mutex<Vec<StreamStatus>>>);
+
+impl StreamStatuses {
+    fn add(&self, name: String) -> usize {
+        let mut statuses = self.0.lock().unwrap();
+        statuses.push(StreamStatus {
+            name,
+            connected: false,
+            connected_since: None,
+            last_event: None,
+            events: 0,
+            failures: 0,
+            last_error: None,
+        });
+        statuses.len() - 1
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut StreamStatus)) {
+        let mut statuses = self.0.lock().unwrap();
+        f(&mut statuses[index]);
+    }
+
+    pub fn snapshot(&self) -> Vec<StreamStatus> {
+        self.0.lock().unwrap().clone()
+    }
+}
 
 #[derive(Debug)]
 pub enum StreamError {
     Http(reqwest::Error),
     HttpStatus(reqwest::StatusCode),
     InvalidContentType,
+    WebSocket(tokio_tungstenite::tungstenite::Error),
+    InvalidAccessToken,
 }
 
-async fn run(url: &str) -> Result<impl Stream<Item = String>, StreamError> {
+/// A message of the WebSocket protocol
+#[derive(Deserialize)]
+struct WebSocketEvent {
+    event: String,
+    payload: Option<String>,
+}
+
+async fn run_eventsource(url: &str, access_token: Option<&str>) -> Result<impl Stream<Item = EventKind>, StreamError> {
     let client = reqwest::Client::new();
-    let res = client.get(url)
-        .timeout(Duration::MAX)
+    let mut req = client.get(url)
+        .timeout(Duration::MAX);
+    if let Some(access_token) = access_token {
+        req = req.bearer_auth(access_token);
+    }
+    let res = req
         .send()
         .await
         .map_err(StreamError::Http)?;
@@ -32,40 +370,236 @@ async fn run(url: &str) -> Result<impl Stream<Item = String>, StreamError> {
     let src = res.bytes_stream()
         .eventsource()
         .filter_map(|result| async {
-            let result = result.ok()?;
-            if result.event == "update" {
-                Some(result)
-            } else {
-                None
-            }
-        })
-        .map(|event| event.data);
+            let event = result.ok()?;
+            EventKind::from_event(&event.event, event.data)
+        });
+    Ok(src)
+}
+
+async fn run_websocket(url: &str, access_token: Option<&str>, subscriptions: &[Subscription]) -> Result<impl Stream<Item = EventKind>, StreamError> {
+    let mut req = url.into_client_request()
+        .map_err(StreamError::WebSocket)?;
+    if let Some(access_token) = access_token {
+        let value = format!("Bearer {access_token}").parse()
+            .map_err(|_| StreamError::InvalidAccessToken)?;
+        req.headers_mut().insert(AUTHORIZATION, value);
+    }
+    let (mut ws, _) = connect_async(req)
+        .await
+        .map_err(StreamError::WebSocket)?;
+    for subscription in subscriptions {
+        ws.send(Message::text(subscription.message().to_string()))
+            .await
+            .map_err(StreamError::WebSocket)?;
+    }
+
+    let src = ws
+        .take_while(|result| futures::future::ready(result.is_ok()))
+        .filter_map(|result| async {
+            let Ok(Message::Text(text)) = result else {
+                return None;
+            };
+            let event: WebSocketEvent = serde_json::from_str(&text).ok()?;
+            EventKind::from_event(&event.event, event.payload?)
+        });
     Ok(src)
 }
 
-pub fn spawn(hosts: impl Iterator<Item = impl Into<String>>) -> Receiver<String> {
+async fn run(stream_source: &StreamSource) -> Result<BoxStream<'static, EventKind>, StreamError> {
+    let access_token = stream_source.access_token.as_deref();
+    match &stream_source.source {
+        Source::EventSource { url } =>
+            Ok(run_eventsource(url, access_token).await?.boxed()),
+        Source::WebSocket { url, subscriptions } =>
+            Ok(run_websocket(url, access_token, subscriptions).await?.boxed()),
+    }
+}
+
+pub fn spawn(sources: impl Iterator<Item = StreamSource>) -> (Receiver<Event>, StreamStatuses) {
     let (tx, rx) = channel(1024);
-    for host in hosts {
-        let host = host.into();
+    let statuses = StreamStatuses::default();
+    for source in sources {
         let tx = tx.clone();
+        let name = source.source.name();
+        let stream_name = Arc::new(name.clone());
+        let statuses = statuses.clone();
+        let index = statuses.add(name.clone());
         tokio::spawn(async move {
+            let mut delay = RECONNECT_MIN;
             loop {
-                match run(&host).await {
-                    Ok(stream) =>
-                        stream.for_each(|post| async {
-                            tx.send(post).await.unwrap();
-                        }).await,
-                    Err(StreamError::Http(e)) =>
-                        tracing::error!("stream http error: {:?}", e),
-                    Err(StreamError::HttpStatus(status)) =>
-                        tracing::error!("stream http status: {:?}", status),
-                    Err(StreamError::InvalidContentType) =>
-                        tracing::error!("stream invalid content-type"),
-                }
+                let error = match run(&source).await {
+                    Ok(mut stream) => {
+                        tracing::info!("stream {} connected", name);
+                        statuses.update(index, |status| {
+                            status.connected = true;
+                            status.connected_since = Some(Utc::now());
+                            status.failures = 0;
+                            status.last_error = None;
+                        });
+                        gauge!("stream_connected", "stream" => name.clone())
+                            .set(1.0);
+
+                        while let Some(kind) = stream.next().await {
+                            let now = Utc::now();
+                            statuses.update(index, |status| {
+                                status.last_event = Some(now);
+                                status.events += 1;
+                            });
+                            counter!("stream_events_total", "stream" => name.clone())
+                                .increment(1);
+                            gauge!("stream_last_event_timestamp_seconds", "stream" => name.clone())
+                                .set(now.timestamp() as f64);
+                            // healthy again
+                            delay = RECONNECT_MIN;
+
+                            if let EventKind::Update(post) | EventKind::StatusUpdate(post) = &kind {
+                                if ! source.filter.is_empty() {
+                                    let Ok(filter_post) = serde_json::from_str(post) else {
+                                        continue;
+                                    };
+                                    if ! source.filter.matches(&filter_post) {
+                                        continue;
+                                    }
+                                }
+                            }
+                            tx.send(Event {
+                                stream: stream_name.clone(),
+                                kind,
+                            }).await.unwrap();
+                        }
+
+                        gauge!("stream_connected", "stream" => name.clone())
+                            .set(0.0);
+                        tracing::warn!("stream {} disconnected", name);
+                        "disconnected".to_string()
+                    }
+                    Err(StreamError::Http(e)) => {
+                        tracing::error!("stream {} http error: {:?}", name, e);
+                        format!("http error: {e}")
+                    }
+                    Err(StreamError::HttpStatus(status)) => {
+                        tracing::error!("stream {} http status: {:?}", name, status);
+                        format!("http status: {status}")
+                    }
+                    Err(StreamError::InvalidContentType) => {
+                        tracing::error!("stream {} invalid content-type", name);
+                        "invalid content-type".to_string()
+                    }
+                    Err(StreamError::WebSocket(e)) => {
+                        tracing::error!("stream {} websocket error: {:?}", name, e);
+                        format!("websocket error: {e}")
+                    }
+                    Err(StreamError::InvalidAccessToken) => {
+                        tracing::error!("stream {} invalid access token", name);
+                        "invalid access token".to_string()
+                    }
+                };
+                statuses.update(index, |status| {
+                    status.connected = false;
+                    status.connected_since = None;
+                    status.failures += 1;
+                    status.last_error = Some(error);
+                });
 
-                sleep(Duration::from_secs(1)).await;
+                sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX);
             }
         });
     }
-    rx
+    (rx, statuses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn source_eventsource() {
+        assert_eq!(
+            Source::from_url("https://fedi.buzz/api/v1/streaming/public"),
+            Source::EventSource { url: "https://fedi.buzz/api/v1/streaming/public".to_string() }
+        );
+    }
+
+    #[test]
+    fn source_websocket_default() {
+        assert_eq!(
+            Source::from_url("wss://example.com/api/v1/streaming"),
+            Source::WebSocket {
+                url: "wss://example.com/api/v1/streaming".to_string(),
+                subscriptions: vec![Subscription::Public],
+            }
+        );
+    }
+
+    #[test]
+    fn source_name() {
+        assert_eq!(
+            Source::from_url("https://example.com/api/v1/streaming/public?access_token=xyz").name(),
+            "https://example.com/api/v1/streaming/public"
+        );
+    }
+
+    #[test]
+    fn stream_source_token_from_url() {
+        let stream_source = StreamSource::from_config(&StreamConfig::Url(
+            "https://example.com/api/v1/streaming/public?access_token=xyz".to_string()
+        ));
+        assert_eq!(stream_source.source, Source::EventSource {
+            url: "https://example.com/api/v1/streaming/public".to_string(),
+        });
+        assert_eq!(stream_source.access_token.as_deref(), Some("xyz"));
+    }
+
+    #[test]
+    fn stream_source_detailed() {
+        let config: StreamConfig = serde_yaml::from_str(r#"
+url: "https://example.com/api/v1/streaming"
+type: websocket
+subscriptions:
+  - public:local
+  - hashtag:rust
+filter:
+  languages: [en]
+"#).unwrap();
+        let stream_source = StreamSource::from_config(&config);
+        assert_eq!(stream_source.source, Source::WebSocket {
+            url: "wss://example.com/api/v1/streaming".to_string(),
+            subscriptions: vec![
+                Subscription::PublicLocal,
+                Subscription::Hashtag("rust".to_string()),
+            ],
+        });
+        assert_eq!(stream_source.access_token, None);
+        assert_eq!(stream_source.filter.languages, Some(vec!["en".to_string()]));
+    }
+
+    #[test]
+    fn filter() {
+        let filter = StreamFilter {
+            languages: Some(vec!["en".to_string(), "de".to_string()]),
+            tags: Some(vec!["Rust".to_string()]),
+        };
+        let post: FilterPost = serde_json::from_str(r#"{"language":"de","tags":[{"name":"rust"}]}"#).unwrap();
+        assert!(filter.matches(&post));
+        let post: FilterPost = serde_json::from_str(r#"{"language":"fr","tags":[{"name":"rust"}]}"#).unwrap();
+        assert!(! filter.matches(&post));
+        let post: FilterPost = serde_json::from_str(r#"{"language":"en","tags":[]}"#).unwrap();
+        assert!(! filter.matches(&post));
+    }
+
+    #[test]
+    fn source_websocket_subscriptions() {
+        assert_eq!(
+            Source::from_url("wss://example.com/api/v1/streaming?stream=public:local&access_token=xyz&tag=rust&tag=fediverse"),
+            Source::WebSocket {
+                url: "wss://example.com/api/v1/streaming?access_token=xyz".to_string(),
+                subscriptions: vec![
+                    Subscription::PublicLocal,
+                    Subscription::Hashtag("rust".to_string()),
+                    Subscription::Hashtag("fediverse".to_string()),
+                ],
+            }
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use crate::{
+    actor::{Actor, ActorKind},
+    filter::Filter,
+    keywords::Keywords,
+};
+
+/// An inbox following an actor, and what it wants delivered
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscriber {
+    pub inbox: String,
+    pub filter: Filter,
+}
+
+/// Subscribers of each relay actor, mirrored from the `follows`
+/// table so that relaying a post needs no database queries
+#[derive(Clone, Default)]
+pub struct Subscriptions {
+    actors: Arc<RwLock<HashMap<String, Arc<Vec<Subscriber>>>>>,
+    /// Followed composite actors by each of their tags, so that posts
+    /// need not be checked against every combination of their
+    /// attributes
+    composites: Arc<RwLock<HashMap<String, Vec<Actor>>>>,
+    /// Matcher for all followed keyword actors, rebuilt when one is
+    /// followed or unfollowed
+    keywords: Arc<RwLock<Arc<Keywords>>>,
+}
+
+/// Parses `actor` if it is a composite
+fn composite(actor: &str) -> Option<Actor> {
+    Actor::from_uri(actor)
+        .filter(|actor| ! actor.kind.composite_tags().is_empty())
+}
+
+fn is_keyword(actor: &str) -> bool {
+    Actor::from_uri(actor)
+        .is_some_and(|actor| matches!(actor.kind, ActorKind::KeywordRelay(_)))
+}
+
+fn keywords_of<T>(actors: &HashMap<String, T>) -> Keywords {
+    Keywords::new(
+        actors.keys()
+            .filter_map(|actor| Actor::from_uri(actor))
+    )
+}
+
+impl Subscriptions {
+    /// Builds the index from `(actor, subscriber)` pairs
+    pub fn replace_all(&self, follows: impl IntoIterator<Item = (String, Subscriber)>) {
+        let mut actors = HashMap::<String, Vec<Subscriber>>::new();
+        for (actor, subscriber) in follows {
+            actors.entry(actor)
+                .or_default()
+                .push(subscriber);
+        }
+        let mut composites = HashMap::<String, Vec<Actor>>::new();
+        for actor in actors.keys().filter_map(|actor| composite(actor)) {
+            for tag in actor.kind.composite_tags() {
+                composites.entry(tag.clone())
+                    .or_default()
+                    .push(actor.clone());
+            }
+        }
+        let keywords = keywords_of(&actors);
+        let actors = actors.into_iter()
+            .map(|(actor, subscribers)| (actor, Arc::new(subscribers)))
+            .collect();
+        *self.actors.write().unwrap() = actors;
+        *self.composites.write().unwrap() = composites;
+        *self.keywords.write().unwrap() = Arc::new(keywords);
+    }
+
+    /// Replaces the subscribers of one actor
+    pub fn set(&self, actor: &str, subscribers: Vec<Subscriber>) {
+        let followed = ! subscribers.is_empty();
+        let mut actors = self.actors.write().unwrap();
+        let was_followed = if followed {
+            actors.insert(actor.to_string(), Arc::new(subscribers))
+                .is_some()
+        } else {
+            actors.remove(actor)
+                .is_some()
+        };
+        if followed != was_followed && is_keyword(actor) {
+            *self.keywords.write().unwrap() = Arc::new(keywords_of(&actors));
+        }
+        drop(actors);
+
+        let Some(actor) = composite(actor) else { return; };
+        let mut composites = self.composites.write().unwrap();
+        for tag in actor.kind.composite_tags() {
+            let tag_composites = composites.entry(tag.clone())
+                .or_default();
+            tag_composites.retain(|composite| *composite != actor);
+            if followed {
+                tag_composites.push(actor.clone());
+            } else if tag_composites.is_empty() {
+                composites.remove(tag);
+            }
+        }
+    }
+
+    pub fn get(&self, actor: &str) -> Arc<Vec<Subscriber>> {
+        self.actors.read().unwrap()
+            .get(actor)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn get_keywords(&self) -> Arc<Keywords> {
+        self.keywords.read().unwrap()
+            .clone()
+    }
+
+    /// Followed composite actors that involve `tag`
+    pub fn get_composites(&self, tag: &str) -> Vec<Actor> {
+        self.composites.read().unwrap()
+            .get(tag)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn subscriber(inbox: &str) -> Subscriber {
+        Subscriber {
+            inbox: inbox.to_string(),
+            filter: Filter::default(),
+        }
+    }
+
+    #[test]
+    fn index() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.replace_all([
+            ("https://relay/tag/a".to_string(), subscriber("https://one/inbox")),
+            ("https://relay/tag/a".to_string(), subscriber("https://two/inbox")),
+            ("https://relay/tag/b".to_string(), subscriber("https://one/inbox")),
+        ]);
+        assert_eq!(subscriptions.get("https://relay/tag/a").len(), 2);
+        assert_eq!(*subscriptions.get("https://relay/tag/b"), vec![subscriber("https://one/inbox")]);
+        assert!(subscriptions.get("https://relay/tag/c").is_empty());
+
+        subscriptions.set("https://relay/tag/b", vec![]);
+        assert!(subscriptions.get("https://relay/tag/b").is_empty());
+        subscriptions.set("https://relay/tag/c", vec![subscriber("https://two/inbox")]);
+        assert_eq!(subscriptions.get("https://relay/tag/c").len(), 1);
+    }
+
+    #[test]
+    fn composites() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.replace_all([
+            ("https://relay/tag/a/language/en".to_string(), subscriber("https://one/inbox")),
+            ("https://relay/tags/a+b".to_string(), subscriber("https://one/inbox")),
+            ("https://relay/tag/a".to_string(), subscriber("https://one/inbox")),
+        ]);
+        assert_eq!(subscriptions.get_composites("a").len(), 2);
+        assert_eq!(subscriptions.get_composites("b"), vec![Actor::from_uri("https://relay/tags/a+b").unwrap()]);
+        assert!(subscriptions.get_composites("c").is_empty());
+
+        subscriptions.set("https://relay/tags/a+b", vec![]);
+        assert_eq!(subscriptions.get_composites("a").len(), 1);
+        assert!(subscriptions.get_composites("b").is_empty());
+        subscriptions.set("https://relay/tag/a/language/en", vec![subscriber("https://two/inbox")]);
+        assert_eq!(subscriptions.get_composites("a").len(), 1);
+        subscriptions.set("https://relay/tags/b+c", vec![subscriber("https://two/inbox")]);
+        assert_eq!(subscriptions.get_composites("c").len(), 1);
+    }
+
+    #[test]
+    fn keywords() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.replace_all([
+            ("https://relay/keyword/rust".to_string(), subscriber("https://one/inbox")),
+            ("https://relay/tag/python".to_string(), subscriber("https://one/inbox")),
+        ]);
+        assert_eq!(subscriptions.get_keywords().matches("rust and python").len(), 1);
+
+        subscriptions.set("https://relay/keyword/python", vec![subscriber("https://two/inbox")]);
+        assert_eq!(subscriptions.get_keywords().matches("rust and python").len(), 2);
+        subscriptions.set("https://relay/keyword/rust", vec![]);
+        assert_eq!(
+            subscriptions.get_keywords().matches("rust and python"),
+            vec![Actor::from_uri("https://relay/keyword/python").unwrap()]
+        );
+    }
+}
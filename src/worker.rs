@@ -0,0 +1,415 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use http::StatusCode;
+use metrics::counter;
+use sigh::PrivateKey;
+use tokio::{
+    sync::{mpsc::{channel, error::TrySendError, Sender}, OwnedSemaphorePermit, Semaphore},
+    time::{interval, sleep, sleep_until, Interval, MissedTickBehavior},
+};
+use crate::{
+    config::{DeliveryConfig, HostLimits},
+    db::Database,
+    error::Error,
+    send,
+};
+
+/// Give up on a delivery after this many failed attempts
+const MAX_ATTEMPTS: u32 = 6;
+/// Delay after the first failure, doubled with every subsequent one
+const BACKOFF_BASE: Duration = Duration::from_secs(10);
+/// Upper bound for the delay between attempts
+const BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+/// Upper bound for honoring a remote's `Retry-After`
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(86400);
+/// How often to refresh the last success of an inbox that keeps working
+const HEALTH_INTERVAL: Duration = Duration::from_secs(3600);
+/// Bounds for the time between requests to a rate-limited host
+const RATE_PERIOD_MIN: Duration = Duration::from_millis(1);
+const RATE_PERIOD_MAX: Duration = Duration::from_secs(3600);
+
+/// Delay before the next request to a host that has failed `errors`
+/// times in a row. Randomized between half and the full exponential
+/// delay so that workers don't retry in lockstep.
+fn backoff(errors: u32) -> Duration {
+    let delay = BACKOFF_BASE
+        .saturating_mul(1 << errors.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX);
+    delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+/// Time between requests for `rate` per second, which must not be
+/// zero for `interval()`
+fn rate_period(rate: f64) -> Duration {
+    Duration::try_from_secs_f64(1.0 / rate)
+        .unwrap_or(RATE_PERIOD_MAX)
+        .clamp(RATE_PERIOD_MIN, RATE_PERIOD_MAX)
+}
+
+pub struct Job {
+    /// `None` if it could not be persisted
+    pub delivery_id: Option<i64>,
+    pub attempts: u32,
+    pub post_url: Arc<String>,
+    pub actor_id: Arc<String>,
+    pub body: Arc<Vec<u8>>,
+    pub key_id: String,
+    pub private_key: Arc<PrivateKey>,
+    pub inbox_url: reqwest::Url,
+}
+
+/// Delivery state shared by all in-flight requests to one host
+#[derive(Default)]
+struct HostState {
+    errors: u32,
+    /// no request is sent before, because of `Retry-After` or errors
+    paused_until: Option<Instant>,
+    /// inbox -> when its success was last recorded
+    healthy: HashMap<String, Instant>,
+}
+
+impl HostState {
+    /// Extends the pause to at least `delay` from now
+    fn pause(&mut self, delay: Duration) {
+        let until = Instant::now() + delay;
+        if self.paused_until.is_none_or(|paused_until| paused_until < until) {
+            self.paused_until = Some(until);
+        }
+    }
+}
+
+/// Everything the deliveries to one host share
+struct Host {
+    name: String,
+    client: Arc<reqwest::Client>,
+    database: Database,
+    state: Mutex<HostState>,
+    semaphore: Arc<Semaphore>,
+    rate: Option<tokio::sync::Mutex<Interval>>,
+}
+
+/// Persisted deliveries that are queued or in progress, so that
+/// replaying them from the database does not queue them twice
+#[derive(Default)]
+struct Claims {
+    claimed: HashSet<i64>,
+    /// Acknowledged since the current replay pass started, as its
+    /// pages may still contain them
+    done: HashSet<i64>,
+}
+
+impl Claims {
+    /// Returns `false` if the delivery is already taken care of
+    fn claim(&mut self, id: i64) -> bool {
+        ! self.done.contains(&id) && self.claimed.insert(id)
+    }
+
+    fn release(&mut self, id: i64) {
+        self.claimed.remove(&id);
+    }
+
+    fn finish(&mut self, id: i64) {
+        self.claimed.remove(&id);
+        self.done.insert(id);
+    }
+}
+
+/// One delivery queue per inbox host
+#[derive(Clone)]
+pub struct Workers {
+    config: DeliveryConfig,
+    client: Arc<reqwest::Client>,
+    database: Database,
+    queues: Arc<Mutex<HashMap<String, Sender<Job>>>>,
+    claims: Arc<Mutex<Claims>>,
+}
+
+impl Workers {
+    pub fn new(config: DeliveryConfig, client: Arc<reqwest::Client>, database: Database) -> Self {
+        Workers {
+            config,
+            client,
+            database,
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            claims: Arc::new(Mutex::new(Claims::default())),
+        }
+    }
+
+    /// Lookup/create worker queue per inbox host
+    fn queue(&self, host: &str) -> Sender<Job> {
+        self.queues.lock().unwrap()
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                let limits = self.config.limits(host);
+                spawn_worker(host.to_string(), limits, self.client.clone(), self.database.clone(), self.claims.clone())
+            })
+            .clone()
+    }
+
+    /// Whether the job is not queued yet, claiming it if so
+    fn claim(&self, job: &Job) -> bool {
+        job.delivery_id
+            .is_none_or(|id| self.claims.lock().unwrap().claim(id))
+    }
+
+    /// Hands a job to the worker of its inbox host, without waiting.
+    ///
+    /// If the queue is full, a persisted job stays in the database
+    /// for the next replay pass. Others are dropped. Returns whether
+    /// the job was queued.
+    pub fn enqueue(&self, job: Job) -> bool {
+        if ! self.claim(&job) {
+            return false;
+        }
+        let host = job.inbox_url.host_str().unwrap_or("").to_string();
+        let Err(e) = self.queue(&host).try_send(job) else { return true; };
+        let job = match e {
+            TrySendError::Full(job) | TrySendError::Closed(job) => job,
+        };
+        if let Some(delivery_id) = job.delivery_id {
+            self.claims.lock().unwrap().release(delivery_id);
+            counter!("relay_jobs_deferred_total", "host" => host)
+                .increment(1);
+        } else {
+            counter!("relay_jobs_dropped_total", "host" => host)
+                .increment(1);
+        }
+        false
+    }
+
+    /// Called before reading the first page of a replay pass
+    pub fn start_replay(&self) {
+        self.claims.lock().unwrap()
+            .done.clear();
+    }
+}
+
+fn spawn_worker(host: String, limits: HostLimits, client: Arc<reqwest::Client>, database: Database, claims: Arc<Mutex<Claims>>) -> Sender<Job> {
+    let (tx, mut rx) = channel::<Job>(limits.queue.max(1));
+
+    tokio::spawn(async move {
+        let host = Arc::new(Host {
+            name: host,
+            client,
+            database,
+            state: Mutex::new(HostState::default()),
+            semaphore: Arc::new(Semaphore::new(limits.concurrency.max(1))),
+            rate: limits.rate
+                .filter(|rate| *rate > 0.0)
+                .map(|rate| {
+                    let mut rate = interval(rate_period(rate));
+                    rate.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                    tokio::sync::Mutex::new(rate)
+                }),
+        });
+
+        while let Some(job) = rx.recv().await {
+            let delivery_id = job.delivery_id;
+            // Only take new jobs from the queue while there is
+            // capacity for them.
+            let permit = host.semaphore.clone().acquire_owned()
+                .await
+                .unwrap();
+            let host = host.clone();
+            let claims = claims.clone();
+            tokio::spawn(async move {
+                deliver(&host, job, permit).await;
+                if let Some(delivery_id) = delivery_id {
+                    claims.lock().unwrap().finish(delivery_id);
+                }
+            });
+        }
+
+        panic!("Worker dead");
+    });
+
+    tx
+}
+
+/// Waits while the host has asked us to back off, or keeps failing
+async fn wait_unpaused(host: &Host) {
+    loop {
+        let paused_until = host.state.lock().unwrap().paused_until;
+        match paused_until {
+            Some(until) if until > Instant::now() =>
+                sleep_until(until.into()).await,
+            _ =>
+                break,
+        }
+    }
+}
+
+/// Sends one job, retrying until it succeeds or is given up on.
+///
+/// `permit` is for the first attempt. Waiting for a paused host
+/// holds it, as nothing can be sent there anyway, but sleeping before
+/// a retry of this job does not.
+async fn deliver(host: &Host, job: Job, permit: OwnedSemaphorePermit) {
+    let Job { delivery_id, mut attempts, post_url, actor_id, key_id, private_key, body, inbox_url } = job;
+
+    let mut permit = Some(permit);
+    loop {
+        let _permit = match permit.take() {
+            Some(permit) => permit,
+            None => host.semaphore.clone().acquire_owned()
+                .await
+                .unwrap(),
+        };
+        wait_unpaused(host).await;
+        if let Some(rate) = &host.rate {
+            rate.lock().await.tick().await;
+        }
+
+        tracing::debug!("relay {} from {} to {}", post_url, actor_id, inbox_url);
+        if let Err(e) = send::send_raw(
+            &host.client, inbox_url.as_str(),
+            &key_id, &private_key, body.clone()
+        ).await {
+            tracing::error!("relay::send {:?}", e);
+            let give_up = {
+                let mut host_state = host.state.lock().unwrap();
+                let permanent = match e {
+                    Error::Status { status, retry_after: Some(delay), .. }
+                        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE => {
+                            // the remote is rate-limiting or overloaded,
+                            // which is neither a failed attempt nor a
+                            // sign of an unhealthy inbox
+                            host_state.pause(delay.min(RETRY_AFTER_MAX));
+                            None
+                        }
+                    Error::Status { status, .. }
+                        if status == StatusCode::NOT_FOUND || status == StatusCode::GONE =>
+                        // the inbox is gone, not the host
+                        Some(true),
+                    _ => {
+                        // wait for time exponential to the number of
+                        // subsequent errors
+                        host_state.errors = host_state.errors.saturating_add(1);
+                        let delay = backoff(host_state.errors);
+                        host_state.pause(delay);
+                        Some(false)
+                    }
+                };
+                let Some(permanent) = permanent else {
+                    counter!("relay_delivery_rate_limited_total", "host" => host.name.clone())
+                        .increment(1);
+                    // retry as soon as the pause is over
+                    continue;
+                };
+                attempts += 1;
+                let give_up = permanent || attempts >= MAX_ATTEMPTS;
+                if give_up {
+                    host_state.healthy.remove(inbox_url.as_str());
+                }
+                give_up
+            };
+            if give_up {
+                tracing::warn!("giving up {} from {} to {} after {} attempts", post_url, actor_id, inbox_url, attempts);
+                counter!("relay_delivery_giveups_total", "host" => host.name.clone())
+                    .increment(1);
+                if let Err(e) = host.database.record_delivery_failure(inbox_url.as_str()).await {
+                    tracing::error!("record_delivery_failure: {}", e);
+                }
+                break;
+            }
+
+            counter!("relay_delivery_retries_total", "host" => host.name.clone())
+                .increment(1);
+            if let Some(delivery_id) = delivery_id {
+                if let Err(e) = host.database.retry_delivery(delivery_id, attempts as i32).await {
+                    tracing::error!("retry_delivery: {}", e);
+                }
+            }
+            // let other jobs use the slot meanwhile
+            drop(_permit);
+            let delay = backoff(attempts);
+            tracing::trace!("delay {} from {} to {} by {:?}", post_url, actor_id, inbox_url, delay);
+            sleep(delay).await;
+        } else {
+            // success
+            systemd::daemon::notify(
+                false, [
+                    (systemd::daemon::STATE_WATCHDOG, "1")
+                ].iter()
+            ).unwrap();
+            let record_success = {
+                let mut host_state = host.state.lock().unwrap();
+                host_state.errors = 0;
+                if host_state.healthy.get(inbox_url.as_str())
+                    .is_none_or(|recorded| recorded.elapsed() >= HEALTH_INTERVAL)
+                {
+                    host_state.healthy.insert(inbox_url.to_string(), Instant::now());
+                    true
+                } else {
+                    false
+                }
+            };
+            if record_success {
+                if let Err(e) = host.database.record_delivery_success(inbox_url.as_str()).await {
+                    tracing::error!("record_delivery_success: {}", e);
+                }
+            }
+            break;
+        }
+    }
+
+    // acknowledge
+    if let Some(delivery_id) = delivery_id {
+        if let Err(e) = host.database.del_delivery(delivery_id).await {
+            tracing::error!("del_delivery: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn claims() {
+        let mut claims = Claims::default();
+        assert!(claims.claim(1));
+        assert!(! claims.claim(1));
+        claims.release(1);
+        assert!(claims.claim(1));
+        claims.finish(1);
+        // still in a page of the current replay pass
+        assert!(! claims.claim(1));
+        claims.done.clear();
+        assert!(claims.claim(1));
+    }
+
+    #[test]
+    fn pause_extends() {
+        let mut host_state = HostState::default();
+        host_state.pause(Duration::from_secs(60));
+        let until = host_state.paused_until.unwrap();
+        host_state.pause(Duration::from_secs(1));
+        assert_eq!(host_state.paused_until, Some(until));
+        host_state.pause(Duration::from_secs(120));
+        assert!(host_state.paused_until.unwrap() > until);
+    }
+
+    #[test]
+    fn rate_bounds() {
+        assert_eq!(rate_period(4.0), Duration::from_millis(250));
+        assert_eq!(rate_period(1e12), RATE_PERIOD_MIN);
+        assert_eq!(rate_period(f64::INFINITY), RATE_PERIOD_MIN);
+        assert_eq!(rate_period(1e-300), RATE_PERIOD_MAX);
+    }
+
+    #[test]
+    fn backoff_bounds() {
+        for _ in 0..100 {
+            let delay = backoff(1);
+            assert!(delay >= BACKOFF_BASE / 2 && delay <= BACKOFF_BASE);
+            let delay = backoff(3);
+            assert!(delay >= BACKOFF_BASE * 2 && delay <= BACKOFF_BASE * 4);
+            let delay = backoff(u32::MAX);
+            assert!(delay >= BACKOFF_MAX / 2 && delay <= BACKOFF_MAX);
+        }
+    }
+}
@@ -4,7 +4,8 @@
         var preEl = document.getElementById(id + "-url");
         function onChange(ev) {
             setTimeout(function() {
-                var value = encodeURIComponent(inputEl.value.replace(/^#/, ""));
+                var value = encodeURIComponent(inputEl.value.replace(/^[#@]/, ""))
+                    .replace(/%40/g, "@");
                 preEl.innerText = value ?
                     "https://" + document.location.host + "/" + id + "/" + value :
                     "\n";
@@ -16,4 +17,6 @@
 
     setup("tag");
     setup("instance");
+    setup("account");
+    setup("keyword");
 })()